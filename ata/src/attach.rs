@@ -0,0 +1,361 @@
+//! Attaching files to a prompt via references such as `@src/main.rs` or `@docs/*.md`.
+//!
+//! Directories and glob patterns are expanded like Git would, so files from
+//! `.gitignore` and hidden files are left out. Files that are named directly
+//! are always attached. Images are only attached when they are named directly,
+//! so that `@.` does not send every image in a project.
+
+use crate::images;
+use crate::images::Loaded;
+use ata::config::Config;
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Characters that can follow a reference in a sentence, such as in `See @README.md.`.
+const TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', ')', '\'', '"'];
+
+/// Files that are not UTF-8 or contain a NUL byte are not sent.
+fn is_binary(contents: &[u8]) -> bool {
+    let start = &contents[..contents.len().min(8000)];
+    start.contains(&0) || std::str::from_utf8(contents).is_err()
+}
+
+fn is_glob(reference: &str) -> bool {
+    reference.contains(['*', '?', '[', '{'])
+}
+
+/// The size in bytes as shown to the user, such as `85.0 KB`.
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=999 => format!("{bytes} B"),
+        1_000..=999_999 => format!("{:.1} KB", bytes as f64 / 1_000.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_000_000.0),
+    }
+}
+
+/// The files in `dir`, without ignored and hidden files.
+fn walk(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkBuilder::new(dir)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// The files that match the glob `pattern`, relative to `base`.
+fn glob(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    let Ok(glob) = GlobBuilder::new(pattern).literal_separator(true).build() else {
+        return vec![];
+    };
+    let matcher = glob.compile_matcher();
+    // Only walk the directory in front of the first component with a wildcard.
+    let root: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect();
+    walk(&base.join(root))
+        .into_iter()
+        .filter(|path| matcher.is_match(path.strip_prefix(base).unwrap_or(path)))
+        .collect()
+}
+
+/// The files for the `reference` without the `@`, or `None` if nothing matches.
+fn resolve(base: &Path, reference: &str) -> Option<Vec<PathBuf>> {
+    if is_glob(reference) {
+        let files = glob(base, reference);
+        return if files.is_empty() { None } else { Some(files) };
+    }
+    let path = base.join(reference);
+    if path.is_file() {
+        Some(vec![path])
+    } else if path.is_dir() {
+        Some(walk(&path))
+    } else {
+        None
+    }
+}
+
+/// What was done with the files that the references in a prompt point to.
+#[derive(Debug, Default, PartialEq)]
+pub struct Attachments {
+    /// The attached files and their sizes.
+    pub files: Vec<(String, u64)>,
+    /// The attached images.
+    pub images: Vec<(String, Loaded)>,
+    /// The files that were left out and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl Attachments {
+    /// A short overview of what is sent along with the prompt, if anything.
+    pub fn preview(&self) -> Option<String> {
+        const MAX_LINES: usize = 10;
+        if self.files.is_empty() && self.images.is_empty() && self.skipped.is_empty() {
+            return None;
+        }
+        let mut lines: Vec<String> = vec![];
+        if !self.files.is_empty() {
+            let total: u64 = self.files.iter().map(|(_, size)| size).sum();
+            let count = self.files.len();
+            let s = if count == 1 { "" } else { "s" };
+            lines.push(format!(
+                "Attaching {count} file{s} ({}):",
+                format_size(total)
+            ));
+            for (name, size) in self.files.iter().take(MAX_LINES) {
+                lines.push(format!("  {name} ({})", format_size(*size)));
+            }
+            if count > MAX_LINES {
+                lines.push(format!("  and {} more", count - MAX_LINES));
+            }
+        }
+        if !self.images.is_empty() {
+            let count = self.images.len();
+            let s = if count == 1 { "" } else { "s" };
+            lines.push(format!("Attaching {count} image{s}:"));
+            for (name, loaded) in &self.images {
+                lines.push(format!("  {name} ({})", loaded.description));
+            }
+        }
+        for (name, reason) in self.skipped.iter().take(MAX_LINES) {
+            lines.push(format!("Skipped {name}: {reason}"));
+        }
+        if self.skipped.len() > MAX_LINES {
+            lines.push(format!(
+                "Skipped {} more files",
+                self.skipped.len() - MAX_LINES
+            ));
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+/// Expand the `@path` references in the `prompt` to the contents of the files.
+/// The references stay in the text without the `@` and the files are added at the end.
+/// Words such as `@someone` that match no file are left as they are.
+/// Images are checked and returned separately since they are not part of the text.
+pub fn expand(prompt: &str, base: &Path, config: &Config) -> (String, Attachments) {
+    let max_bytes = config.max_attachment_bytes;
+    let mut attachments = Attachments::default();
+    let mut text = String::new();
+    let mut blocks: Vec<String> = vec![];
+    let mut seen: Vec<PathBuf> = vec![];
+    let mut remaining = max_bytes;
+    let mut rest = prompt;
+    while !rest.is_empty() {
+        let word_start = rest
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or(rest.len());
+        text.push_str(&rest[..word_start]);
+        rest = &rest[word_start..];
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..word_end];
+        rest = &rest[word_end..];
+        let Some(reference) = word.strip_prefix('@') else {
+            text.push_str(word);
+            continue;
+        };
+        let trimmed = reference.trim_end_matches(TRAILING);
+        let resolved = [reference, trimmed]
+            .into_iter()
+            .filter(|reference| !reference.is_empty())
+            .find_map(|reference| resolve(base, reference));
+        let Some(files) = resolved else {
+            text.push_str(word);
+            continue;
+        };
+        text.push_str(&word[1..]);
+        let named = [base.join(reference), base.join(trimmed)];
+        for path in files {
+            if seen.contains(&path) {
+                continue;
+            }
+            seen.push(path.clone());
+            let name = path
+                .strip_prefix(base)
+                .unwrap_or(&path)
+                .display()
+                .to_string();
+            if images::is_image_path(&path) {
+                if !named.contains(&path) {
+                    let reason = "images are only attached when named directly".to_string();
+                    attachments.skipped.push((name, reason));
+                    continue;
+                }
+                match images::load_file(&path, config) {
+                    Ok(loaded) => attachments.images.push((name, loaded)),
+                    Err(reason) => attachments.skipped.push((name, reason)),
+                }
+                continue;
+            }
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if remaining < size {
+                let reason = format!(
+                    "{} would exceed the {} `max_attachment_bytes` limit ({} left)",
+                    format_size(size),
+                    format_size(max_bytes),
+                    format_size(remaining)
+                );
+                attachments.skipped.push((name, reason));
+                continue;
+            }
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    attachments.skipped.push((name, e.to_string()));
+                    continue;
+                }
+            };
+            let size = contents.len() as u64;
+            if is_binary(&contents) {
+                attachments
+                    .skipped
+                    .push((name, "not a text file".to_string()));
+            } else {
+                remaining = remaining.saturating_sub(size);
+                let contents = String::from_utf8_lossy(&contents);
+                let contents = contents.trim_end_matches('\n');
+                blocks.push(format!("<file path=\"{name}\">\n{contents}\n</file>"));
+                attachments.files.push((name, size));
+            }
+        }
+    }
+    if !blocks.is_empty() {
+        text = format!("{}\n\n{}", text.trim_end(), blocks.join("\n\n"));
+    }
+    (text, attachments)
+}
+
+/// Like `expand`, but with the `piped` text, such as from stdin, added after the prompt.
+/// References in the piped text are not expanded since the user did not type them.
+pub fn expand_with_piped(
+    prompt: &str,
+    piped: &str,
+    base: &Path,
+    config: &Config,
+) -> (String, Attachments) {
+    let (text, attachments) = expand(prompt, base, config);
+    let text = match (text.is_empty(), piped.is_empty()) {
+        (_, true) => text,
+        (true, false) => piped.to_string(),
+        (false, false) => format!("{text}\n\n{piped}"),
+    };
+    (text, attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use pretty_assertions::assert_eq;
+
+    fn config(max_attachment_bytes: u64) -> Config {
+        test_config(&format!("max_attachment_bytes = {max_attachment_bytes}"))
+    }
+
+    fn project(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ata-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.join("README.md"), "# Demo\n").unwrap();
+        fs::write(dir.join("notes.md"), "Notes\n").unwrap();
+        fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+        fs::write(dir.join("src/data.bin"), [0x89, b'P', b'N', b'G', 0, 1]).unwrap();
+        fs::write(dir.join("src/logo.png"), "<svg></svg>").unwrap();
+        fs::write(dir.join("target/out.rs"), "ignored").unwrap();
+        dir
+    }
+
+    #[test]
+    fn references_are_expanded() {
+        let dir = project("references");
+        let (text, attachments) =
+            expand("Explain @src/main.rs, thanks @someone", &dir, &config(1000));
+        assert_eq!(
+            text,
+            "Explain src/main.rs, thanks @someone\n\n\
+            <file path=\"src/main.rs\">\nfn main() {}\n</file>"
+        );
+        assert_eq!(attachments.files, vec![("src/main.rs".to_string(), 13)]);
+
+        let (_, attachments) = expand("@src @*.md @src/main.rs", &dir, &config(1000));
+        let names: Vec<&str> = attachments.files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["src/lib.rs", "src/main.rs", "README.md", "notes.md"]
+        );
+        assert_eq!(
+            attachments.skipped,
+            vec![
+                ("src/data.bin".to_string(), "not a text file".to_string()),
+                (
+                    "src/logo.png".to_string(),
+                    "images are only attached when named directly".to_string()
+                )
+            ]
+        );
+
+        let (_, attachments) = expand("@src/logo.png", &dir, &config(1000));
+        assert_eq!(
+            attachments.skipped,
+            vec![(
+                "src/logo.png".to_string(),
+                "not a PNG, JPEG or WebP image".to_string()
+            )]
+        );
+
+        let (text, attachments) = expand("@**/*.rs", &dir, &config(1000));
+        assert!(!text.contains("ignored"));
+        assert_eq!(attachments.files.len(), 2);
+
+        let (text, attachments) = expand("No @files here", &dir, &config(1000));
+        assert_eq!(text, "No @files here");
+        assert_eq!(attachments.preview(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn piped_text_is_not_expanded() {
+        let dir = project("piped");
+        let piped = "Downloaded text with @notes.md in it";
+        let (text, attachments) =
+            expand_with_piped("Summarize @README.md", piped, &dir, &config(1000));
+        assert_eq!(
+            text,
+            "Summarize README.md\n\n<file path=\"README.md\">\n# Demo\n</file>\n\n\
+            Downloaded text with @notes.md in it"
+        );
+        assert_eq!(attachments.files, vec![("README.md".to_string(), 7)]);
+
+        let (text, attachments) = expand_with_piped("", piped, &dir, &config(1000));
+        assert_eq!(text, piped);
+        assert_eq!(attachments.preview(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_limit() {
+        let dir = std::env::temp_dir().join(format!("ata-attach-limit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a".repeat(600)).unwrap();
+        fs::write(dir.join("b.txt"), "b".repeat(600)).unwrap();
+        let (text, attachments) = expand("@a.txt @b.txt", &dir, &config(1000));
+        assert!(!text.contains("bbb"));
+        assert_eq!(
+            attachments.preview().unwrap(),
+            "Attaching 1 file (600 B):\n  a.txt (600 B)\n\
+            Skipped b.txt: 600 B would exceed the 1.0 KB `max_attachment_bytes` limit (400 B left)"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
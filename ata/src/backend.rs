@@ -0,0 +1,192 @@
+//! Chat backends which send messages to a model and stream back typed events.
+//!
+//! A backend only knows how to talk to an API. Printing the events, aborting
+//! and retrying are done by the caller, see `prompt::request`.
+
+mod anthropic;
+mod http;
+pub mod mock;
+mod ollama;
+mod openai;
+mod proxy;
+mod tls;
+
+pub use crate::error::Error;
+
+use crate::config::Config;
+use crate::config::Provider;
+use futures_util::future::BoxFuture;
+use futures_util::Stream;
+use serde::Deserialize;
+use serde::Serialize;
+use std::pin::Pin;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+/// An image that is sent along with a message to a model that can see.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    /// Such as `image/png`.
+    pub media_type: String,
+    /// The file in base64.
+    pub data: String,
+}
+
+impl Image {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+/// One message in a conversation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    /// Each API expects the images in another place, so the backends add them.
+    #[serde(skip)]
+    pub images: Vec<Image>,
+}
+
+impl Message {
+    pub fn system(content: &str) -> Self {
+        Message {
+            role: Role::System,
+            content: content.to_string(),
+            images: vec![],
+        }
+    }
+
+    pub fn user(content: &str) -> Self {
+        Message {
+            role: Role::User,
+            content: content.to_string(),
+            images: vec![],
+        }
+    }
+
+    pub fn with_images(mut self, images: Vec<Image>) -> Self {
+        self.images = images;
+        self
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Message {
+            role: Role::Assistant,
+            content: content.to_string(),
+            images: vec![],
+        }
+    }
+}
+
+/// The messages and parameters for one response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatRequest {
+    pub model: String,
+    pub system_prompt: Option<String>,
+    /// The conversation so far which ends with the prompt.
+    pub messages: Vec<Message>,
+    pub max_tokens: i64,
+    pub temperature: f64,
+    /// Whether to ask for the token counts if the API only sends them on request.
+    pub include_usage: bool,
+}
+
+impl ChatRequest {
+    pub fn new(config: &Config, messages: &[Message]) -> Self {
+        ChatRequest {
+            model: config.model.clone(),
+            system_prompt: config.system_prompt().map(|s| s.to_string()),
+            messages: messages.to_vec(),
+            max_tokens: config.max_tokens,
+            temperature: config.temperature,
+            include_usage: config.show_usage,
+        }
+    }
+}
+
+/// Number of tokens that a response cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Some APIs send the prompt and completion tokens in separate events.
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// Something that happened in the response stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The next part of the response text.
+    Text(String),
+    /// Why the model stopped, such as `stop`, `length` or `end_turn`.
+    Finish(String),
+    /// Token counts which some APIs send near the end of the stream.
+    Usage(Usage),
+}
+
+/// The events of one response. The stream ends after the first error.
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Error>> + Send>>;
+
+pub trait Backend: Send + Sync {
+    /// Send the request and return the events of the streamed response.
+    /// Nothing is sent until the stream is polled.
+    fn chat(&self, request: &ChatRequest) -> EventStream;
+
+    /// The names of the models which the server provides.
+    fn models(&self) -> BoxFuture<'static, Result<Vec<String>, Error>> {
+        Box::pin(async { Err(models_not_supported()) })
+    }
+}
+
+fn models_not_supported() -> Error {
+    Error::config("Listing the models is not supported for this provider.")
+}
+
+/// The backend for the `provider` in the configuration.
+pub fn from_config(config: &Config) -> Result<Box<dyn Backend>, Error> {
+    let url = config.chat_url()?;
+    let api_key = config.api_key().map(|key| key.to_string());
+    let backend: Box<dyn Backend> = match config.provider {
+        Provider::OpenAI => Box::new(http::HttpBackend::new(
+            url,
+            openai::OpenAI { api_key },
+            config,
+        )?),
+        Provider::Anthropic => Box::new(http::HttpBackend::new(
+            url,
+            anthropic::Anthropic { api_key },
+            config,
+        )?),
+        Provider::Ollama => {
+            let tags_url = format!("{}/api/tags", config.base_url());
+            let tags_url = tags_url
+                .parse()
+                .map_err(|e| Error::config(format!("Invalid `base_url` ({tags_url}): {e}")))?;
+            Box::new(http::HttpBackend::new(
+                url,
+                ollama::Ollama { api_key, tags_url },
+                config,
+            )?)
+        }
+    };
+    Ok(backend)
+}
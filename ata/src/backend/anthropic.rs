@@ -0,0 +1,186 @@
+//! The Anthropic Messages API.
+
+use super::http::api_error;
+use super::http::sse_decoder;
+use super::http::Api;
+use super::http::Decoder;
+use super::ChatRequest;
+use super::Error;
+use super::Event;
+use super::Message;
+use super::Usage;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Uri;
+use serde_json::json;
+use serde_json::Value;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+pub struct Anthropic {
+    pub api_key: Option<String>,
+}
+
+/// The message with the images as content blocks in front of the text.
+fn message2json(message: &Message) -> Value {
+    if message.images.is_empty() {
+        return json!(message);
+    }
+    let mut blocks: Vec<Value> = message
+        .images
+        .iter()
+        .map(|image| {
+            json!({"type": "image", "source": {
+                "type": "base64",
+                "media_type": image.media_type,
+                "data": image.data
+            }})
+        })
+        .collect();
+    blocks.push(json!({"type": "text", "text": message.content}));
+    json!({"role": message.role, "content": blocks})
+}
+
+fn body(request: &ChatRequest) -> Value {
+    let messages: Vec<Value> = request.messages.iter().map(message2json).collect();
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages,
+        "max_tokens": request.max_tokens,
+        "temperature": request.temperature,
+        "stream": true
+    });
+    // Anthropic takes the system prompt as a parameter instead of a message.
+    if let Some(system_prompt) = &request.system_prompt {
+        body["system"] = json!(system_prompt);
+    }
+    body
+}
+
+fn tokens(v: &Value) -> u64 {
+    v.as_u64().unwrap_or_default()
+}
+
+/// Anthropic sends typed events such as `message_start`, `content_block_delta`
+/// and `message_stop`, see <https://docs.anthropic.com/en/api/messages-streaming>.
+fn parse(v: &Value) -> Result<Vec<Event>, Error> {
+    let events = match v["type"].as_str() {
+        Some("content_block_delta") => match v["delta"]["text"].as_str() {
+            Some(text) => vec![Event::Text(text.to_string())],
+            None => vec![],
+        },
+        // The input tokens are counted at the start and the output tokens at the end.
+        Some("message_start") => vec![Event::Usage(Usage {
+            prompt_tokens: tokens(&v["message"]["usage"]["input_tokens"]),
+            completion_tokens: 0,
+        })],
+        Some("message_delta") => {
+            let mut events = vec![];
+            if let Some(reason) = v["delta"]["stop_reason"].as_str() {
+                events.push(Event::Finish(reason.to_string()));
+            }
+            if v["usage"].is_object() {
+                events.push(Event::Usage(Usage {
+                    prompt_tokens: 0,
+                    completion_tokens: tokens(&v["usage"]["output_tokens"]),
+                }));
+            }
+            events
+        }
+        Some("error") => {
+            let error = api_error(None, v).unwrap_or(Error::Protocol(v.to_string()));
+            return Err(error);
+        }
+        // New event types may be added, so ignore unknown ones.
+        Some(_) => vec![],
+        None => return Err(Error::Protocol(v.to_string())),
+    };
+    Ok(events)
+}
+
+impl Api for Anthropic {
+    fn request(&self, url: &Uri, request: &ChatRequest) -> Result<Request<Body>, Error> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("Content-Type", "application/json")
+            .header("anthropic-version", ANTHROPIC_VERSION);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        builder
+            .body(Body::from(body(request).to_string()))
+            .map_err(|e| Error::config(e.to_string()))
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        sse_decoder(parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Image;
+    use crate::config::test_config;
+    use crate::retry::Retry;
+
+    #[test]
+    fn system_prompt_is_a_parameter() {
+        let config = test_config("provider = \"anthropic\"\nsystem_prompt = \"Be brief.\"");
+        let request = ChatRequest::new(&config, &[Message::user("foo")]);
+        let body = body(&request);
+        assert_eq!(body["system"], "Be brief.");
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "foo"}])
+        );
+        assert_eq!(body["max_tokens"], 10);
+
+        let image = Image {
+            media_type: "image/jpeg".to_string(),
+            data: "/9j/".to_string(),
+        };
+        let message = Message::user("What is this?").with_images(vec![image]);
+        assert_eq!(
+            message2json(&message),
+            json!({"role": "user", "content": [
+                {"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "/9j/"}},
+                {"type": "text", "text": "What is this?"}
+            ]})
+        );
+    }
+
+    #[test]
+    fn parse_events() {
+        let v = json!({
+            "type": "content_block_delta",
+            "index": 0,
+            "delta": {"type": "text_delta", "text": "Hi"}
+        });
+        assert_eq!(parse(&v), Ok(vec![Event::Text("Hi".to_string())]));
+        let v = json!({"type": "message_stop"});
+        assert_eq!(parse(&v), Ok(vec![]));
+        let v = json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": "end_turn"},
+            "usage": {"output_tokens": 15}
+        });
+        let usage = Usage {
+            prompt_tokens: 0,
+            completion_tokens: 15,
+        };
+        assert_eq!(
+            parse(&v),
+            Ok(vec![
+                Event::Finish("end_turn".to_string()),
+                Event::Usage(usage)
+            ])
+        );
+        let v = json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}});
+        let error = parse(&v).unwrap_err();
+        assert_eq!(error.to_string(), "Received an error message: Overloaded");
+        assert!(Retry::default().delay(1, &error).is_some());
+    }
+}
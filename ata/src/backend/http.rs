@@ -0,0 +1,503 @@
+//! The part of the HTTP backends that does not depend on the wire format.
+
+use super::models_not_supported;
+use super::proxy::Proxies;
+use super::proxy::ProxyConnector;
+use super::tls::tls_config;
+use super::Backend;
+use super::ChatRequest;
+use super::Error;
+use super::Event;
+use super::EventStream;
+use crate::config::Config;
+use crate::config::Timeout;
+use crate::sse;
+use async_stream::try_stream;
+use futures_util::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper::Client;
+use hyper::HeaderMap;
+use hyper::Request;
+use hyper::Uri;
+use hyper_rustls::HttpsConnector;
+use hyper_rustls::HttpsConnectorBuilder;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// How the request and the streamed response of an API look.
+pub trait Api: Send + Sync + 'static {
+    fn request(&self, url: &Uri, request: &ChatRequest) -> Result<Request<Body>, Error>;
+
+    /// A decoder for the body of one response.
+    fn decoder(&self) -> Box<dyn Decoder>;
+
+    /// The URL that lists the models, if the API can do that.
+    fn models_url(&self) -> Option<Uri> {
+        None
+    }
+
+    /// The model names in the response from `models_url`.
+    fn parse_models(&self, _v: &Value) -> Result<Vec<String>, Error> {
+        Ok(vec![])
+    }
+}
+
+/// Turns the chunks of a response body into events.
+pub trait Decoder: Send {
+    /// Add a chunk of the body and return the events that were completed by it.
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Event>, Error>;
+
+    /// Return the remaining events when the body ends.
+    fn finish(&mut self) -> Result<Vec<Event>, Error>;
+
+    /// Whether the server marked the end of the response.
+    fn is_done(&self) -> bool {
+        false
+    }
+}
+
+fn error_message(v: &Value) -> Option<String> {
+    let error = v.get("error")?;
+    // Some servers send only a message instead of an object.
+    match error.as_str() {
+        Some(message) => Some(message.to_string()),
+        None => error["message"].as_str().map(|s| s.to_string()),
+    }
+}
+
+/// The error in a JSON object such as `{"error": {"type": "..", "message": ".."}}`.
+pub fn api_error(status: Option<u16>, v: &Value) -> Option<Error> {
+    let message = error_message(v)?;
+    let kind = v["error"]["type"].as_str().map(|s| s.to_string());
+    Some(Error::Api {
+        status,
+        kind,
+        message,
+        retry_after: None,
+    })
+}
+
+/// A duration such as `1s`, `6m0s` or `20ms` as sent in the `x-ratelimit-reset-*` headers.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let mut secs = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let unit_start = rest.find(|c: char| c.is_ascii_alphabetic())?;
+        let number: f64 = rest[..unit_start].parse().ok()?;
+        rest = &rest[unit_start..];
+        let unit_end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let factor = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        secs += number * factor;
+        rest = &rest[unit_end..];
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// How long the server asked to wait via `Retry-After` or the rate limit headers of OpenAI.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(value) = header("retry-after") {
+        let value = value.trim();
+        if let Ok(secs) = value.parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(date.duration_since(SystemTime::now()).unwrap_or_default());
+        }
+    }
+    // Only the limits that ran out matter.
+    ["requests", "tokens"]
+        .iter()
+        .filter(|limit| header(&format!("x-ratelimit-remaining-{limit}")) == Some("0"))
+        .filter_map(|limit| parse_duration(header(&format!("x-ratelimit-reset-{limit}"))?))
+        .max()
+}
+
+/// Errors are sent as a plain JSON body instead of as events.
+fn status_error(status: u16, headers: &HeaderMap, body: &[u8]) -> Error {
+    let body = String::from_utf8_lossy(body);
+    let mut error = match serde_json::from_str::<Value>(&body) {
+        Ok(v) => api_error(Some(status), &v),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| Error::Api {
+        status: Some(status),
+        kind: None,
+        message: body.to_string(),
+        retry_after: None,
+    });
+    if let Error::Api { retry_after: r, .. } = &mut error {
+        *r = retry_after(headers);
+    }
+    error
+}
+
+/// Server-sent events which each contain a JSON object.
+struct SseDecoder {
+    sse: sse::Decoder,
+    parse: fn(&Value) -> Result<Vec<Event>, Error>,
+}
+
+impl SseDecoder {
+    fn parse_all(&self, events: Vec<sse::Event>) -> Result<Vec<Event>, Error> {
+        let mut parsed = vec![];
+        for event in events {
+            let v: Value = serde_json::from_str(&event.data)
+                .map_err(|_| Error::Protocol(event.data.clone()))?;
+            parsed.extend((self.parse)(&v)?);
+        }
+        Ok(parsed)
+    }
+}
+
+impl Decoder for SseDecoder {
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Event>, Error> {
+        let events = self.sse.feed(chunk);
+        self.parse_all(events)
+    }
+
+    fn finish(&mut self) -> Result<Vec<Event>, Error> {
+        let events = self.sse.finish();
+        self.parse_all(events)
+    }
+
+    fn is_done(&self) -> bool {
+        self.sse.is_done()
+    }
+}
+
+/// A decoder for server-sent events which parses the data of each event with `parse`.
+pub fn sse_decoder(parse: fn(&Value) -> Result<Vec<Event>, Error>) -> Box<dyn Decoder> {
+    Box::new(SseDecoder {
+        sse: sse::Decoder::new(),
+        parse,
+    })
+}
+
+/// Newline-delimited JSON where each line contains one object.
+struct NdjsonDecoder {
+    buffer: Vec<u8>,
+    parse: fn(&Value) -> Result<Vec<Event>, Error>,
+}
+
+impl NdjsonDecoder {
+    fn parse_line(&self, line: &[u8]) -> Result<Vec<Event>, Error> {
+        let line = String::from_utf8_lossy(line);
+        if line.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let v: Value =
+            serde_json::from_str(&line).map_err(|_| Error::Protocol(line.to_string()))?;
+        (self.parse)(&v)
+    }
+}
+
+impl Decoder for NdjsonDecoder {
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Event>, Error> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        // Like with SSE, a newline byte never occurs inside a multi-byte character.
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            events.extend(self.parse_line(&line)?);
+        }
+        Ok(events)
+    }
+
+    fn finish(&mut self) -> Result<Vec<Event>, Error> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.parse_line(&rest)
+    }
+}
+
+/// A decoder for newline-delimited JSON which parses each line with `parse`.
+pub fn ndjson_decoder(parse: fn(&Value) -> Result<Vec<Event>, Error>) -> Box<dyn Decoder> {
+    Box::new(NdjsonDecoder {
+        buffer: vec![],
+        parse,
+    })
+}
+
+type HttpClient = Client<HttpsConnector<ProxyConnector>>;
+
+fn client(config: &Config) -> Result<HttpClient, Error> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(config.timeout.connect());
+    let proxies = Proxies::new(config.proxy.as_deref(), |name| std::env::var(name).ok())?;
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config(config)?)
+        // Plain HTTP URLs are restricted by `Config::chat_url`.
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(ProxyConnector::new(http, proxies));
+    Ok(Client::builder().build::<_, hyper::Body>(https))
+}
+
+/// Wait for `future` or return `None` when it takes longer than `limit`.
+async fn within<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Whether the connector gave up because of `Timeout::connect_ms`.
+fn is_connect_timeout(error: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            return error.kind() == std::io::ErrorKind::TimedOut;
+        }
+        source = error.source();
+    }
+    false
+}
+
+fn connect_error(error: hyper::Error, timeout: &Timeout) -> Error {
+    if error.is_connect() && is_connect_timeout(&error) {
+        let limit = timeout.connect().unwrap_or_default();
+        return Error::Timeout(format!(
+            "Could not connect to the server within {limit:?} (`timeout.connect_ms`)."
+        ));
+    }
+    let message = format!("Could not reach the server: {error}");
+    // Retrying won't help when the certificate of the server is not trusted.
+    if message.contains("UnknownIssuer") {
+        return Error::Config {
+            message,
+            hint: Some("Set `ca_bundle` to the certificate of the CA that signed the certificate of the server.".to_string()),
+        };
+    }
+    Error::Http(message)
+}
+
+fn first_token_error(timeout: &Timeout) -> Error {
+    let limit = timeout.first_token().unwrap_or_default();
+    Error::Timeout(format!(
+        "The server did not respond within {limit:?} (`timeout.first_token_ms`)."
+    ))
+}
+
+fn idle_error(timeout: &Timeout) -> Error {
+    let limit = timeout.idle().unwrap_or_default();
+    Error::Timeout(format!(
+        "The server stopped sending the response for {limit:?} (`timeout.idle_ms`)."
+    ))
+}
+
+pub struct HttpBackend<A> {
+    url: Uri,
+    api: Arc<A>,
+    client: HttpClient,
+    timeout: Timeout,
+}
+
+impl<A: Api> HttpBackend<A> {
+    pub fn new(url: Uri, api: A, config: &Config) -> Result<Self, Error> {
+        Ok(HttpBackend {
+            url,
+            api: Arc::new(api),
+            client: client(config)?,
+            timeout: config.timeout.clone(),
+        })
+    }
+}
+
+impl<A: Api> Backend for HttpBackend<A> {
+    fn chat(&self, request: &ChatRequest) -> EventStream {
+        let req = self.api.request(&self.url, request);
+        let api = self.api.clone();
+        let client = self.client.clone();
+        let timeout = self.timeout.clone();
+        Box::pin(try_stream! {
+            let start = Instant::now();
+            // The first token limit includes the time to send the request and receive the headers.
+            let first_token_left = || {
+                timeout
+                    .first_token()
+                    .map(|limit| limit.saturating_sub(start.elapsed()))
+            };
+            let response = within(first_token_left(), client.request(req?))
+                .await
+                .ok_or_else(|| first_token_error(&timeout))?
+                .map_err(|e| connect_error(e, &timeout))?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let mut body = response.into_body();
+            if !status.is_success() {
+                let body = within(timeout.idle(), hyper::body::to_bytes(&mut body))
+                    .await
+                    .ok_or_else(|| idle_error(&timeout))?
+                    .map_err(|e| Error::Http(format!("Connection broke: {e}")))?;
+                Err(status_error(status.as_u16(), &headers, &body))?;
+            }
+
+            let mut decoder = api.decoder();
+            let mut received = false;
+            loop {
+                let limit = if received { timeout.idle() } else { first_token_left() };
+                let chunk = match within(limit, body.data()).await {
+                    Some(Some(chunk)) => chunk,
+                    Some(None) => break,
+                    None if received => Err(idle_error(&timeout))?,
+                    None => Err(first_token_error(&timeout))?,
+                };
+                let chunk = chunk.map_err(|e| Error::Http(format!("Connection broke: {e}")))?;
+                for event in decoder.feed(&chunk)? {
+                    received = true;
+                    yield event;
+                }
+                if decoder.is_done() {
+                    break;
+                }
+            }
+            for event in decoder.finish()? {
+                yield event;
+            }
+        })
+    }
+
+    fn models(&self) -> BoxFuture<'static, Result<Vec<String>, Error>> {
+        let api = self.api.clone();
+        let client = self.client.clone();
+        let timeout = self.timeout.clone();
+        Box::pin(async move {
+            let url = match api.models_url() {
+                Some(url) => url,
+                None => return Err(models_not_supported()),
+            };
+            let response = within(timeout.first_token(), client.get(url))
+                .await
+                .ok_or_else(|| first_token_error(&timeout))?
+                .map_err(|e| connect_error(e, &timeout))?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = within(timeout.idle(), hyper::body::to_bytes(response.into_body()))
+                .await
+                .ok_or_else(|| idle_error(&timeout))?
+                .map_err(|e| Error::Http(format!("Connection broke: {e}")))?;
+            if !status.is_success() {
+                return Err(status_error(status.as_u16(), &headers, &body));
+            }
+            let v: Value = serde_json::from_slice(&body)
+                .map_err(|_| Error::Protocol(String::from_utf8_lossy(&body).to_string()))?;
+            api.parse_models(&v)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::openai::OpenAI;
+    use crate::backend::Message;
+    use crate::config::test_config;
+    use futures_util::StreamExt;
+    use hyper::header::HeaderValue;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn retry_after_headers() {
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_duration("soon"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("0"),
+        );
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("2s"));
+        headers.insert(
+            "x-ratelimit-remaining-tokens",
+            HeaderValue::from_static("900"),
+        );
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("1m"));
+        let body = br#"{"error": {"type": "requests", "message": "Rate limit reached"}}"#;
+        let error = status_error(429, &headers, body);
+        assert_eq!(
+            error,
+            Error::Api {
+                status: Some(429),
+                kind: Some("requests".to_string()),
+                message: "Rate limit reached".to_string(),
+                retry_after: Some(Duration::from_secs(2)),
+            }
+        );
+
+        headers.insert("retry-after", HeaderValue::from_static("7"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        let date = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(10));
+        headers.insert("retry-after", HeaderValue::from_str(&date).unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    /// A server that sends the first part of a response and then goes quiet.
+    async fn stalling_server() -> Uri {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            let chunk = r#"data: {"choices": [{"delta": {"content": "Hi"}}]}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{chunk}\n\n\r\n",
+                chunk.len() + 2
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        url.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_times_out() {
+        let config = test_config("[timeout]\nidle_ms = 100");
+        let url = stalling_server().await;
+        let backend = HttpBackend::new(url, OpenAI { api_key: None }, &config).unwrap();
+        let request = ChatRequest {
+            model: "gpt-4".to_string(),
+            system_prompt: None,
+            messages: vec![Message::user("Hi")],
+            max_tokens: 10,
+            temperature: 0.8,
+            include_usage: false,
+        };
+        let events: Vec<Result<Event, Error>> = backend.chat(&request).collect().await;
+        assert_eq!(events[0], Ok(Event::Text("Hi".to_string())));
+        assert_eq!(
+            events[1],
+            Err(Error::Timeout(
+                "The server stopped sending the response for 100ms (`timeout.idle_ms`)."
+                    .to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! An in-memory backend for testing the code that handles the events.
+
+use super::Backend;
+use super::ChatRequest;
+use super::Error;
+use super::Event;
+use super::EventStream;
+use futures_util::stream;
+use futures_util::StreamExt;
+use std::sync::Mutex;
+
+/// Responds with the same events to every request and remembers the requests.
+pub struct MockBackend {
+    events: Vec<Result<Event, Error>>,
+    /// Whether the stream stays open after the events as if the server hangs.
+    hang: bool,
+    pub requests: Mutex<Vec<ChatRequest>>,
+}
+
+impl MockBackend {
+    pub fn new(events: Vec<Result<Event, Error>>) -> Self {
+        MockBackend {
+            events,
+            hang: false,
+            requests: Mutex::new(vec![]),
+        }
+    }
+
+    /// Respond with the texts followed by `Finish("stop")`.
+    pub fn texts(texts: &[&str]) -> Self {
+        let mut events: Vec<Result<Event, Error>> = texts
+            .iter()
+            .map(|text| Ok(Event::Text(text.to_string())))
+            .collect();
+        events.push(Ok(Event::Finish("stop".to_string())));
+        MockBackend::new(events)
+    }
+
+    /// Never end the stream after the events.
+    pub fn hanging(mut self) -> Self {
+        self.hang = true;
+        self
+    }
+}
+
+impl Backend for MockBackend {
+    fn chat(&self, request: &ChatRequest) -> EventStream {
+        self.requests.lock().unwrap().push(request.clone());
+        let events = stream::iter(self.events.clone());
+        if self.hang {
+            events.chain(stream::pending()).boxed()
+        } else {
+            events.boxed()
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! The native API of Ollama, see <https://github.com/ollama/ollama/blob/main/docs/api.md>.
+//!
+//! Unlike the other APIs, Ollama streams newline-delimited JSON instead of
+//! server-sent events and needs no API key.
+
+use super::http::api_error;
+use super::http::ndjson_decoder;
+use super::http::Api;
+use super::http::Decoder;
+use super::ChatRequest;
+use super::Error;
+use super::Event;
+use super::Message;
+use super::Usage;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Uri;
+use serde_json::json;
+use serde_json::Value;
+
+pub struct Ollama {
+    /// Only needed when the server is behind a proxy that checks it.
+    pub api_key: Option<String>,
+    /// The endpoint that lists the local models.
+    pub tags_url: Uri,
+}
+
+fn body(request: &ChatRequest) -> Value {
+    let mut messages = vec![];
+    if let Some(system_prompt) = &request.system_prompt {
+        messages.push(Message::system(system_prompt));
+    }
+    messages.extend_from_slice(&request.messages);
+    let messages: Vec<Value> = messages
+        .iter()
+        .map(|message| {
+            let mut value = json!(message);
+            if !message.images.is_empty() {
+                let images: Vec<&str> = message.images.iter().map(|i| i.data.as_str()).collect();
+                value["images"] = json!(images);
+            }
+            value
+        })
+        .collect();
+    json!({
+        "model": request.model,
+        "messages": messages,
+        "stream": true,
+        "options": {
+            "num_predict": request.max_tokens,
+            "temperature": request.temperature
+        }
+    })
+}
+
+fn parse(v: &Value) -> Result<Vec<Event>, Error> {
+    if let Some(error) = api_error(None, v) {
+        return Err(error);
+    }
+    let mut events = vec![];
+    match v["message"]["content"].as_str() {
+        Some(text) => events.push(Event::Text(text.to_string())),
+        None if v.get("done").is_none() => return Err(Error::Protocol(v.to_string())),
+        None => (),
+    }
+    // The last object contains the statistics of the whole response.
+    if v["done"].as_bool() == Some(true) {
+        if let Some(reason) = v["done_reason"].as_str() {
+            events.push(Event::Finish(reason.to_string()));
+        }
+        events.push(Event::Usage(Usage {
+            prompt_tokens: v["prompt_eval_count"].as_u64().unwrap_or_default(),
+            completion_tokens: v["eval_count"].as_u64().unwrap_or_default(),
+        }));
+    }
+    Ok(events)
+}
+
+impl Api for Ollama {
+    fn request(&self, url: &Uri, request: &ChatRequest) -> Result<Request<Body>, Error> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("Content-Type", "application/json");
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("Authorization", format!("Bearer {api_key}"));
+        }
+        builder
+            .body(Body::from(body(request).to_string()))
+            .map_err(|e| Error::config(e.to_string()))
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        ndjson_decoder(parse)
+    }
+
+    fn models_url(&self) -> Option<Uri> {
+        Some(self.tags_url.clone())
+    }
+
+    fn parse_models(&self, v: &Value) -> Result<Vec<String>, Error> {
+        let models = v["models"]
+            .as_array()
+            .ok_or_else(|| Error::Protocol(v.to_string()))?;
+        let names = models
+            .iter()
+            .filter_map(|model| model["name"].as_str())
+            .map(|name| name.to_string())
+            .collect();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Image;
+    use crate::config::test_config;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn images_are_base64() {
+        let config = test_config("provider = \"ollama\"");
+        let image = Image {
+            media_type: "image/png".to_string(),
+            data: "iVBO".to_string(),
+        };
+        let message = Message::user("What is this?").with_images(vec![image]);
+        let body = body(&ChatRequest::new(&config, &[message]));
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "What is this?", "images": ["iVBO"]}])
+        );
+    }
+
+    #[test]
+    fn ndjson_stream() {
+        let body = concat!(
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            "\n",
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"lö"},"done":false}"#,
+            "\n",
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":""},"done":true,"#,
+            r#""done_reason":"stop","prompt_eval_count":26,"eval_count":2}"#,
+        )
+        .as_bytes();
+        // Split inside the `ö` to check that partial characters are kept.
+        let split = body.iter().position(|b| *b == 0xc3).unwrap() + 1;
+        let mut decoder = ndjson_decoder(parse);
+        let mut events = decoder.feed(&body[..split]).unwrap();
+        events.extend(decoder.feed(&body[split..]).unwrap());
+        events.extend(decoder.finish().unwrap());
+        let usage = Usage {
+            prompt_tokens: 26,
+            completion_tokens: 2,
+        };
+        assert_eq!(
+            events,
+            vec![
+                Event::Text("Hel".to_string()),
+                Event::Text("lö".to_string()),
+                Event::Text("".to_string()),
+                Event::Finish("stop".to_string()),
+                Event::Usage(usage),
+            ]
+        );
+
+        let error = parse(&json!({"error": "model \"foo\" not found"})).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Received an error message: model \"foo\" not found"
+        );
+    }
+
+    #[test]
+    fn list_models() {
+        let ollama = Ollama {
+            api_key: None,
+            tags_url: "http://localhost:11434/api/tags".parse().unwrap(),
+        };
+        let v = json!({"models": [
+            {"name": "llama3.2:latest", "size": 2019393189},
+            {"name": "qwen2.5-coder:7b", "size": 4683087332u64}
+        ]});
+        assert_eq!(
+            ollama.parse_models(&v).unwrap(),
+            vec!["llama3.2:latest", "qwen2.5-coder:7b"]
+        );
+    }
+}
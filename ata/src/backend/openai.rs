@@ -0,0 +1,201 @@
+//! The OpenAI chat completions API which many other servers also provide.
+
+use super::http::api_error;
+use super::http::sse_decoder;
+use super::http::Api;
+use super::http::Decoder;
+use super::ChatRequest;
+use super::Error;
+use super::Event;
+use super::Message;
+use super::Usage;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Uri;
+use serde_json::json;
+use serde_json::Value;
+
+pub struct OpenAI {
+    pub api_key: Option<String>,
+}
+
+fn value2unquoted_text(value: &serde_json::Value) -> String {
+    value.as_str().unwrap().to_string()
+}
+
+fn messages2json(messages: &[Message]) -> Value {
+    let messages: Vec<Value> = messages
+        .iter()
+        .map(|message| {
+            if message.images.is_empty() {
+                return json!(message);
+            }
+            let mut parts = vec![json!({"type": "text", "text": message.content})];
+            for image in &message.images {
+                parts.push(json!({"type": "image_url", "image_url": {"url": image.data_url()}}));
+            }
+            json!({"role": message.role, "content": parts})
+        })
+        .collect();
+    Value::Array(messages)
+}
+
+fn body(request: &ChatRequest) -> Value {
+    let mut all_messages = vec![];
+    if let Some(system_prompt) = &request.system_prompt {
+        all_messages.push(Message::system(system_prompt));
+    }
+    all_messages.extend_from_slice(&request.messages);
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages2json(&all_messages),
+        "max_tokens": request.max_tokens,
+        "temperature": request.temperature,
+        "stream": true
+    });
+    // Adds a last chunk with the token counts of the whole response. Only sent
+    // when needed since some compatible servers reject unknown fields.
+    if request.include_usage {
+        body["stream_options"] = json!({"include_usage": true});
+    }
+    body
+}
+
+fn parse(v: &Value) -> Result<Vec<Event>, Error> {
+    let mut events = vec![];
+    if let Some(choices) = v.get("choices") {
+        // We request only one completion.
+        let choice: &Value = &choices[0];
+        // Ignoring wrong responses to avoid crashes and content being absent
+        // when switching "role" (`"role":"assistant"`).
+        if choice["delta"]["content"].is_string() {
+            let text = value2unquoted_text(&choice["delta"]["content"]);
+            events.push(Event::Text(text));
+        }
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            events.push(Event::Finish(reason.to_string()));
+        }
+    } else if let Some(error) = api_error(None, v) {
+        return Err(error);
+    } else if v.get("usage").is_none() {
+        return Err(Error::Protocol(v.to_string()));
+    }
+    if let Some(usage) = v.get("usage").filter(|usage| usage.is_object()) {
+        events.push(Event::Usage(Usage {
+            prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or_default(),
+            completion_tokens: usage["completion_tokens"].as_u64().unwrap_or_default(),
+        }));
+    }
+    Ok(events)
+}
+
+impl Api for OpenAI {
+    fn request(&self, url: &Uri, request: &ChatRequest) -> Result<Request<Body>, Error> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("Content-Type", "application/json");
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("Authorization", format!("Bearer {api_key}"));
+        }
+        builder
+            .body(Body::from(body(request).to_string()))
+            .map_err(|e| Error::config(e.to_string()))
+    }
+
+    fn decoder(&self) -> Box<dyn Decoder> {
+        sse_decoder(parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Image;
+    use crate::config::test_config;
+    use crate::config::Config;
+    use crate::retry::Retry;
+
+    #[test]
+    fn attached_files_are_sent_unchanged() {
+        let prompt = "Explain a.txt\n\n<file path=\"a.txt\">\nsay \"hi\"\n</file>";
+        let request = ChatRequest::new(&test_config(""), &[Message::user(prompt)]);
+        let sent = body(&request).to_string();
+        assert!(sent.contains(r#"say \"hi\""#));
+        let received: Value = serde_json::from_str(&sent).unwrap();
+        assert_eq!(received["messages"][0]["content"], prompt);
+    }
+
+    #[test]
+    fn usage_is_only_requested_when_shown() {
+        let config = test_config("");
+        let request = ChatRequest::new(&config, &[Message::user("Hi")]);
+        assert_eq!(body(&request)["stream_options"]["include_usage"], true);
+        let config = Config {
+            show_usage: false,
+            ..config
+        };
+        let request = ChatRequest::new(&config, &[Message::user("Hi")]);
+        assert!(body(&request).get("stream_options").is_none());
+    }
+
+    #[test]
+    fn value_is_unquoted() {
+        use super::*;
+        let v: Value = serde_json::from_str(r#"{"a": "1"}"#).unwrap();
+        assert_eq!(value2unquoted_text(&v["a"]), "1");
+    }
+
+    #[test]
+    fn history_is_sent_in_order() {
+        let messages = vec![
+            Message::system("qux"),
+            Message::user("foo"),
+            Message::assistant("bar"),
+            Message::user("baz"),
+        ];
+        let expected = json!([
+            {"role": "system", "content": "qux"},
+            {"role": "user", "content": "foo"},
+            {"role": "assistant", "content": "bar"},
+            {"role": "user", "content": "baz"}
+        ]);
+        assert_eq!(messages2json(&messages), expected);
+    }
+
+    #[test]
+    fn images_are_content_parts() {
+        let image = Image {
+            media_type: "image/png".to_string(),
+            data: "iVBO".to_string(),
+        };
+        let messages = vec![Message::user("What is this?").with_images(vec![image])];
+        let expected = json!([{"role": "user", "content": [
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBO"}}
+        ]}]);
+        assert_eq!(messages2json(&messages), expected);
+    }
+
+    #[test]
+    fn parse_events() {
+        let v = json!({"choices": [{"delta": {"content": "Hi"}}]});
+        assert_eq!(parse(&v), Ok(vec![Event::Text("Hi".to_string())]));
+        let v = json!({"choices": [{"delta": {"role": "assistant"}}]});
+        assert_eq!(parse(&v), Ok(vec![]));
+        let v = json!({"choices": [{"delta": {}, "finish_reason": "length"}]});
+        assert_eq!(parse(&v), Ok(vec![Event::Finish("length".to_string())]));
+        let v = json!({"choices": [], "usage": {"prompt_tokens": 3, "completion_tokens": 5}});
+        let usage = Usage {
+            prompt_tokens: 3,
+            completion_tokens: 5,
+        };
+        assert_eq!(parse(&v), Ok(vec![Event::Usage(usage)]));
+
+        let v = json!({"error": {"type": "server_error", "message": "Oops"}});
+        let error = parse(&v).unwrap_err();
+        assert!(Retry::default().delay(1, &error).is_some());
+        assert!(matches!(parse(&json!({"foo": 1})), Err(Error::Protocol(_))));
+    }
+}
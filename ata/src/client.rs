@@ -0,0 +1,46 @@
+use crate::backend;
+use crate::backend::Backend;
+use crate::backend::ChatRequest;
+use crate::backend::Error;
+use crate::backend::EventStream;
+use crate::backend::Message;
+use crate::config::Config;
+
+/// Sends chats to the provider in the configuration.
+pub struct Client {
+    config: Config,
+    backend: Box<dyn Backend>,
+}
+
+impl Client {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let backend = backend::from_config(&config)?;
+        Ok(Client { config, backend })
+    }
+
+    /// Use another backend such as `backend::mock::MockBackend` in tests.
+    pub fn with_backend(config: Config, backend: Box<dyn Backend>) -> Self {
+        Client { config, backend }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Use other settings from now on.
+    pub fn set_config(&mut self, config: Config) -> Result<(), Error> {
+        self.backend = backend::from_config(&config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Send the messages after the system prompt, if any, and stream the response.
+    pub fn chat(&self, messages: &[Message]) -> EventStream {
+        self.backend.chat(&ChatRequest::new(&self.config, messages))
+    }
+
+    /// The names of the models which the server provides.
+    pub async fn models(&self) -> Result<Vec<String>, Error> {
+        self.backend.models().await
+    }
+}
@@ -0,0 +1,130 @@
+//! Copying responses to the system clipboard via the OSC 52 escape sequence.
+//!
+//! The terminal sets the clipboard, so this also works over SSH and in tmux
+//! without a platform clipboard library.
+
+use crate::markdown::is_closing_fence;
+use crate::markdown::opening_fence;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::env;
+use std::io::Write;
+
+/// The contents of the fenced code blocks in the Markdown `text`.
+/// A block that is not closed runs until the end of the text.
+pub fn code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current: Option<(usize, &str, Vec<&str>)> = None;
+    for line in text.lines() {
+        match &mut current {
+            Some((indent, fence, lines)) => {
+                if is_closing_fence(line, fence) {
+                    blocks.push(lines.join("\n"));
+                    current = None;
+                } else {
+                    // The indentation of the fence is removed from the lines in the block.
+                    let spaces = line.len() - line.trim_start_matches(' ').len();
+                    lines.push(&line[spaces.min(*indent)..]);
+                }
+            }
+            None => current = opening_fence(line).map(|(indent, fence)| (indent, fence, vec![])),
+        }
+    }
+    if let Some((_, _, lines)) = current {
+        blocks.push(lines.join("\n"));
+    }
+    blocks
+}
+
+/// The escape sequence that asks the terminal to put `text` on the clipboard.
+/// Inside tmux, the sequence is passed through to the outer terminal.
+fn osc52(text: &str, tmux: bool) -> String {
+    let sequence = format!("\x1b]52;c;{}\x07", BASE64.encode(text));
+    if tmux {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else {
+        sequence
+    }
+}
+
+/// Put `text` on the clipboard of the terminal.
+pub fn copy(text: &str) {
+    let tmux = env::var_os("TMUX").is_some();
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "{}", osc52(text, tmux));
+    let _ = stdout.flush();
+}
+
+/// The part of the `response` that `/copy` copies: everything or the `n`th code block.
+pub fn selection(response: &str, n: Option<usize>) -> Result<String, String> {
+    if response.is_empty() {
+        return Err("There is no response to copy yet.".to_string());
+    }
+    let Some(n) = n else {
+        return Ok(response.to_string());
+    };
+    let blocks = code_blocks(response);
+    match blocks.len() {
+        0 => Err("The last response contains no code blocks.".to_string()),
+        len if n == 0 || len < n => Err(format!(
+            "Expected a number from 1 to {len} since the last response contains {len} code block{}.",
+            if len == 1 { "" } else { "s" }
+        )),
+        _ => Ok(blocks[n - 1].clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn fenced_code_blocks() {
+        let text = "\
+Run this:
+
+```rust
+fn main() {
+    println!(\"```\");
+}
+```
+
+Or this:
+
+  ~~~~
+  ~~~
+  ls
+  ~~~~~
+
+```sh
+unclosed";
+        assert_eq!(
+            code_blocks(text),
+            vec![
+                "fn main() {\n    println!(\"```\");\n}",
+                "~~~\nls",
+                "unclosed"
+            ]
+        );
+        assert!(code_blocks("Use ``` for code.\n``inline``").is_empty());
+        assert!(code_blocks("    ```\n    indented code\n    ```").is_empty());
+    }
+
+    #[test]
+    fn osc52_sequences() {
+        assert_eq!(osc52("hi", false), "\x1b]52;c;aGk=\x07");
+        assert_eq!(osc52("hi", true), "\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\");
+    }
+
+    #[test]
+    fn selections() {
+        let response = "A\n```\none\n```\n```\ntwo\n```";
+        assert_eq!(selection(response, None), Ok(response.to_string()));
+        assert_eq!(selection(response, Some(2)), Ok("two".to_string()));
+        assert!(selection(response, Some(0)).is_err());
+        assert!(selection(response, Some(3)).is_err());
+        assert!(selection("No code", Some(1)).is_err());
+        assert!(selection("", None).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Slash commands such as `/model` which are typed in the REPL instead of a prompt.
+
+use ata::config::Config;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+/help                 Print this overview
+/config               Print the current settings
+/model <NAME>         Use another model
+/temperature <VALUE>  Set the sampling temperature (0 to 2)
+/max_tokens <N>       Set the maximum number of tokens in the response
+/persona [NAME]       Print the personas or switch to one (`none` for the default)
+/markdown <on|off>    Render the Markdown in responses or print the plain text
+/clear                Start a new conversation
+/edit                 Write the prompt in $VISUAL or $EDITOR, starting from the last prompt
+/image <PATH>         Send a PNG, JPEG or WebP image with the next prompt
+/copy [N]             Copy the last response or its Nth code block to the clipboard
+
+Changes only apply to this session. To keep them, edit the configuration file.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Config,
+    Model(String),
+    Temperature(f64),
+    MaxTokens(i64),
+    ListPersonas,
+    Persona(Option<String>),
+    Markdown(bool),
+    Clear,
+    Copy(Option<usize>),
+    Edit,
+    Image(PathBuf),
+}
+
+/// What the REPL has to do after a command was executed.
+#[derive(Debug, PartialEq)]
+pub enum Effect {
+    Nothing,
+    /// The settings changed, so the next request should use the new config.
+    ConfigChanged,
+    /// Forget the conversation.
+    Clear,
+    /// Copy the last response, or the code block with this number, to the clipboard.
+    Copy(Option<usize>),
+    /// Write the next prompt in the editor.
+    Edit,
+    /// Send the image with the next prompt.
+    Image(PathBuf),
+}
+
+fn parse_argument<T: std::str::FromStr>(
+    name: &str,
+    argument: Option<&str>,
+    expected: &str,
+) -> Result<T, String> {
+    let argument = argument.ok_or(format!("Usage: /{name} <{expected}>"))?;
+    argument
+        .parse()
+        .map_err(|_| format!("Expected {expected} for /{name} but got `{argument}`."))
+}
+
+/// Parse a line which starts with a slash.
+/// Returns `None` if the line is a prompt such as `/etc/hosts is empty, why?`.
+pub fn parse(line: &str) -> Option<Result<Command, String>> {
+    let mut parts = line.split_whitespace();
+    let name = parts.next()?.strip_prefix('/')?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    let rest: Vec<&str> = parts.collect();
+    let argument = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.join(" "))
+    };
+    let argument = argument.as_deref();
+    let command = match name {
+        "help" => Ok(Command::Help),
+        "config" => Ok(Command::Config),
+        "clear" => Ok(Command::Clear),
+        "edit" => Ok(Command::Edit),
+        "image" => match argument {
+            // Terminals quote or escape the path of a file that is dropped on them.
+            Some(path) => Ok(Command::Image(PathBuf::from(
+                path.trim_matches(['\'', '"']).replace("\\ ", " "),
+            ))),
+            None => Err("Usage: /image <PATH>".to_string()),
+        },
+        "model" => match argument {
+            Some(model) => Ok(Command::Model(model.to_string())),
+            None => Err("Usage: /model <NAME>".to_string()),
+        },
+        "temperature" => parse_argument(name, argument, "a number").and_then(|temperature: f64| {
+            if (0.0..=2.0).contains(&temperature) {
+                Ok(Command::Temperature(temperature))
+            } else {
+                Err("The temperature should be between 0 and 2.".to_string())
+            }
+        }),
+        "max_tokens" => {
+            parse_argument(name, argument, "a positive integer").and_then(|max_tokens: i64| {
+                if max_tokens > 0 {
+                    Ok(Command::MaxTokens(max_tokens))
+                } else {
+                    Err("The maximum number of tokens should be positive.".to_string())
+                }
+            })
+        }
+        "copy" => match argument {
+            None => Ok(Command::Copy(None)),
+            Some(_) => parse_argument(name, argument, "a positive integer")
+                .map(|n: usize| Command::Copy(Some(n))),
+        },
+        "persona" => match argument {
+            None => Ok(Command::ListPersonas),
+            Some("none") => Ok(Command::Persona(None)),
+            Some(name) => Ok(Command::Persona(Some(name.to_string()))),
+        },
+        "markdown" => match argument {
+            Some("on") => Ok(Command::Markdown(true)),
+            Some("off") => Ok(Command::Markdown(false)),
+            _ => Err("Usage: /markdown <on|off>".to_string()),
+        },
+        _ => Err(format!(
+            "Unknown command `/{name}`. Type `/help` to see the available commands."
+        )),
+    };
+    Some(command)
+}
+
+fn print_config(config: &Config) {
+    println!("provider: {}", config.provider);
+    println!("model: {}", config.model);
+    println!("max_tokens: {}", config.max_tokens);
+    println!("temperature: {}", config.temperature);
+    if let Some(persona) = &config.persona {
+        println!("persona: {persona}");
+    }
+    println!("markdown: {}", config.markdown);
+}
+
+fn print_personas(config: &Config) {
+    if config.personas.is_empty() {
+        println!("No personas are defined in the `[personas]` table of the configuration.");
+        return;
+    }
+    for name in config.personas.keys() {
+        let marker = if config.persona.as_ref() == Some(name) {
+            "*"
+        } else {
+            " "
+        };
+        println!("{marker} {name}");
+    }
+    println!("\nType `/persona <NAME>` to switch or `/persona none` for the default.");
+}
+
+/// Execute the command and print what happened.
+pub fn execute(command: Command, config: &mut Config) -> Result<Effect, String> {
+    let effect = match command {
+        Command::Help => {
+            println!("{HELP}");
+            Effect::Nothing
+        }
+        Command::Config => {
+            print_config(config);
+            Effect::Nothing
+        }
+        Command::ListPersonas => {
+            print_personas(config);
+            Effect::Nothing
+        }
+        Command::Clear => {
+            println!("Started a new conversation.");
+            Effect::Clear
+        }
+        Command::Copy(n) => Effect::Copy(n),
+        Command::Edit => Effect::Edit,
+        Command::Image(path) => Effect::Image(path),
+        Command::Model(model) => {
+            println!("Using model `{model}`.");
+            config.model = model;
+            Effect::ConfigChanged
+        }
+        Command::Temperature(temperature) => {
+            println!("Using temperature {temperature}.");
+            config.temperature = temperature;
+            Effect::ConfigChanged
+        }
+        Command::MaxTokens(max_tokens) => {
+            println!("Using max_tokens {max_tokens}.");
+            config.max_tokens = max_tokens;
+            Effect::ConfigChanged
+        }
+        Command::Persona(name) => {
+            config
+                .set_persona(name.as_deref())
+                .map_err(|e| e.to_string())?;
+            match name {
+                Some(name) => println!("Using persona `{name}`."),
+                None => println!("Using the default system prompt."),
+            }
+            Effect::ConfigChanged
+        }
+        Command::Markdown(markdown) => {
+            let state = if markdown { "on" } else { "off" };
+            println!("Markdown rendering is {state}.");
+            config.markdown = markdown;
+            Effect::ConfigChanged
+        }
+    };
+    Ok(effect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    fn config() -> Config {
+        test_config("[personas]\npirate = \"Talk like a pirate.\"")
+    }
+
+    #[test]
+    fn prompts_are_not_commands() {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("/etc/hosts is empty, why?"), None);
+        assert_eq!(parse("/"), None);
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("/help"), Some(Ok(Command::Help)));
+        assert_eq!(
+            parse(" /model  gpt-4o "),
+            Some(Ok(Command::Model("gpt-4o".to_string())))
+        );
+        assert_eq!(
+            parse("/temperature 0.2"),
+            Some(Ok(Command::Temperature(0.2)))
+        );
+        assert_eq!(parse("/max_tokens 100"), Some(Ok(Command::MaxTokens(100))));
+        assert_eq!(parse("/persona"), Some(Ok(Command::ListPersonas)));
+        assert_eq!(parse("/persona none"), Some(Ok(Command::Persona(None))));
+        assert_eq!(parse("/markdown off"), Some(Ok(Command::Markdown(false))));
+        assert_eq!(parse("/edit"), Some(Ok(Command::Edit)));
+        assert_eq!(
+            parse("/image 'Screenshot 1.png'"),
+            Some(Ok(Command::Image(PathBuf::from("Screenshot 1.png"))))
+        );
+        assert_eq!(
+            parse("/image Screenshot\\ 2.png"),
+            Some(Ok(Command::Image(PathBuf::from("Screenshot 2.png"))))
+        );
+        assert_eq!(parse("/copy"), Some(Ok(Command::Copy(None))));
+        assert_eq!(parse("/copy 2"), Some(Ok(Command::Copy(Some(2)))));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(matches!(parse("/temperature hot"), Some(Err(_))));
+        assert!(matches!(parse("/temperature 3"), Some(Err(_))));
+        assert!(matches!(parse("/max_tokens -1"), Some(Err(_))));
+        assert!(matches!(parse("/model"), Some(Err(_))));
+        assert!(matches!(parse("/copy last"), Some(Err(_))));
+        assert!(matches!(parse("/foo"), Some(Err(_))));
+    }
+
+    #[test]
+    fn execute_updates_config() {
+        let mut config = config();
+        let effect = execute(Command::Temperature(0.1), &mut config);
+        assert_eq!(effect, Ok(Effect::ConfigChanged));
+        assert_eq!(config.temperature, 0.1);
+
+        execute(Command::Model("gpt-4o".to_string()), &mut config).unwrap();
+        assert_eq!(config.model, "gpt-4o");
+
+        execute(Command::Persona(Some("pirate".to_string())), &mut config).unwrap();
+        assert_eq!(config.system_prompt(), Some("Talk like a pirate."));
+        assert!(execute(Command::Persona(Some("poet".to_string())), &mut config).is_err());
+
+        assert_eq!(execute(Command::Clear, &mut config), Ok(Effect::Clear));
+    }
+}
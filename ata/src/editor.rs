@@ -0,0 +1,102 @@
+//! Composing a prompt in the editor from `$VISUAL` or `$EDITOR`.
+
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::ExitStatus;
+
+/// The editor command from the environment, like Git does.
+fn editor_command<F: Fn(&str) -> Option<String>>(var: F) -> String {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(var)
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| {
+            let default = if cfg!(windows) { "notepad" } else { "vi" };
+            default.to_string()
+        })
+}
+
+/// Run the `editor` on the file at `path`.
+/// The shell runs the command, so editors such as `code --wait` work too.
+fn run(editor: &str, path: &Path) -> std::io::Result<ExitStatus> {
+    if cfg!(windows) {
+        Command::new("cmd")
+            .args(["/C", &format!("{editor} \"{}\"", path.display())])
+            .status()
+    } else {
+        Command::new("sh")
+            .args(["-c", &format!("{editor} \"$1\""), "sh"])
+            .arg(path)
+            .status()
+    }
+}
+
+fn edit_file(editor: &str, path: &Path, initial: &str) -> Result<String, String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("Could not create {path:?}: {e}"))?;
+    file.write_all(initial.as_bytes())
+        .map_err(|e| format!("Could not write {path:?}: {e}"))?;
+    drop(file);
+    let status = run(editor, path).map_err(|e| format!("Could not start `{editor}`: {e}"))?;
+    if !status.success() {
+        return Err(format!(
+            "The editor `{editor}` failed ({status}), so the prompt was not sent."
+        ));
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("Could not read {path:?}: {e}"))?;
+    Ok(text.trim_end().to_string())
+}
+
+/// Open the editor on a temporary file with `initial` and return the saved text.
+pub fn compose(initial: &str) -> Result<String, String> {
+    let editor = editor_command(|name| env::var(name).ok());
+    let name = format!("ata-prompt-{}-{}.md", std::process::id(), fastrand::u32(..));
+    let path = env::temp_dir().join(name);
+    let result = edit_file(&editor, &path, initial);
+    let _ = fs::remove_file(&path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editor_from_the_environment() {
+        let vars = |visual: Option<&str>, editor: Option<&str>| {
+            editor_command(|name| match name {
+                "VISUAL" => visual.map(str::to_string),
+                "EDITOR" => editor.map(str::to_string),
+                _ => None,
+            })
+        };
+        assert_eq!(vars(Some("code --wait"), Some("nano")), "code --wait");
+        assert_eq!(vars(Some(""), Some("nano")), "nano");
+        assert!(!vars(None, None).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_text_is_returned() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("ata-editor-test-{}.md", std::process::id()));
+        let text = edit_file("printf 'Edited\\n\\n' >", &path, "Draft").unwrap();
+        assert_eq!(text, "Edited");
+        fs::remove_file(&path).unwrap();
+
+        let text = edit_file("true", &path, "Draft").unwrap();
+        assert_eq!(text, "Draft");
+        fs::remove_file(&path).unwrap();
+
+        let error = edit_file("false", &path, "Draft").unwrap_err();
+        assert!(error.starts_with("The editor `false` failed"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! The errors of ata with messages that say what went wrong and how to fix it.
+
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Why a configuration could not be used or a response could not be completed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The configuration is missing, malformed or contains an invalid value.
+    Config {
+        message: String,
+        /// How to fix the configuration.
+        hint: Option<String>,
+    },
+    /// A file could not be read or written.
+    Io { path: PathBuf, message: String },
+    /// The server could not be reached or the connection broke.
+    Http(String),
+    /// The server took longer than one of the limits in the `[timeout]` section.
+    Timeout(String),
+    /// The server responded with an error, either as status or in the stream.
+    Api {
+        /// HTTP status if the error was not sent inside the stream.
+        status: Option<u16>,
+        /// Error type such as `server_error` or `overloaded_error`.
+        kind: Option<String>,
+        message: String,
+        /// How long the server asked to wait before sending the request again.
+        retry_after: Option<Duration>,
+    },
+    /// The response could not be understood.
+    Protocol(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The line and column, both starting at 1, of a byte offset in `contents`.
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// A suggestion for common mistakes in the configuration file.
+fn toml_hint(message: &str) -> Option<String> {
+    let examples = [
+        ("model", "model = \"gpt-4o\""),
+        ("max_tokens", "max_tokens = 2048"),
+        ("temperature", "temperature = 0.8"),
+    ];
+    for (field, example) in examples {
+        if message.contains(&format!("missing field `{field}`")) {
+            return Some(format!("Add a line such as `{example}`."));
+        }
+    }
+    if message.contains("invalid type") {
+        return Some(
+            "Text must be quoted, such as `model = \"gpt-4o\"`, but numbers and booleans not."
+                .to_string(),
+        );
+    }
+    if message.contains("unknown variant") {
+        return Some("Check the spelling of the value.".to_string());
+    }
+    None
+}
+
+impl Error {
+    pub fn config(message: impl Into<String>) -> Self {
+        Error::Config {
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    /// An error for a malformed configuration, with the position of the problem.
+    /// Pass the `path` if the contents were read from a file.
+    pub fn toml(path: Option<&Path>, contents: &str, error: &toml::de::Error) -> Self {
+        let mut message = match path {
+            Some(path) => format!("Could not parse the configuration at {path:?}"),
+            None => "Could not parse the configuration".to_string(),
+        };
+        if let Some(span) = error.span() {
+            let (line, column) = line_column(contents, span.start);
+            message.push_str(&format!(" on line {line}, column {column}"));
+        }
+        let reason = error.message().trim_end();
+        message.push_str(&format!(": {reason}"));
+        Error::Config {
+            message,
+            hint: toml_hint(reason),
+        }
+    }
+
+    /// An error for the file at `path`, where `action` is for example "read".
+    pub fn io(action: &str, path: &Path, error: &std::io::Error) -> Self {
+        Error::Io {
+            path: path.to_path_buf(),
+            message: format!("Could not {action} {path:?}: {error}"),
+        }
+    }
+
+    /// The exit code of the `ata` binary for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Api { .. } => 3,
+            Error::Http(_) | Error::Timeout(_) => 4,
+            Error::Protocol(_) => 5,
+            Error::Config { .. } => 6,
+            Error::Io { .. } => 7,
+        }
+    }
+
+    fn hint(&self) -> Option<&str> {
+        match self {
+            Error::Config { hint, .. } => hint.as_deref(),
+            Error::Http(_) => Some("Check the internet connection, `base_url` and `proxy`."),
+            Error::Timeout(_) => {
+                Some("Raise the limit in the `[timeout]` section of the configuration if the server is slow.")
+            }
+            Error::Api {
+                status: Some(401 | 403),
+                ..
+            } => Some("Check the API key from `api_key`, `api_key_cmd`, `api_key_file` or the environment."),
+            Error::Api {
+                status: Some(404), ..
+            } => Some("Check `model`, `base_url` and `chat_path` in the configuration."),
+            Error::Protocol(_) => Some("Check whether `provider` matches the API at `base_url`."),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config { message, .. } => write!(f, "{message}")?,
+            Error::Io { message, .. } => write!(f, "{message}")?,
+            Error::Http(msg) | Error::Timeout(msg) => write!(f, "{msg}")?,
+            Error::Api {
+                status: Some(status),
+                message,
+                ..
+            } => write!(f, "Server responded with status {status}: {message}")?,
+            Error::Api {
+                status: None,
+                message,
+                ..
+            } => write!(f, "Received an error message: {message}")?,
+            Error::Protocol(msg) => {
+                write!(f, "Response didn't contain the expected fields: {msg}")?
+            }
+        }
+        match self.hint() {
+            Some(hint) => write!(f, "\nHint: {hint}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn toml_errors_have_a_position_and_hint() {
+        let contents =
+            "api_key = \"\"\nmodel = \"gpt-4\"\nmax_tokens = \"many\"\ntemperature = 0.8";
+        let error = toml::from_str::<Config>(contents).unwrap_err();
+        let error = Error::toml(Some(Path::new("ata.toml")), contents, &error);
+        let message = error.to_string();
+        assert!(message.starts_with(
+            "Could not parse the configuration at \"ata.toml\" on line 3, column 14: invalid type"
+        ));
+        assert!(message.ends_with("\nHint: Text must be quoted, such as `model = \"gpt-4o\"`, but numbers and booleans not."));
+
+        let contents = "model = \"gpt-4\"\ntemperature = 0.8";
+        let error = toml::from_str::<Config>(contents).unwrap_err();
+        let error = Error::toml(None, contents, &error);
+        assert_eq!(
+            error.to_string(),
+            "Could not parse the configuration on line 1, column 1: missing field `max_tokens`\n\
+            Hint: Add a line such as `max_tokens = 2048`."
+        );
+        assert_eq!(error.exit_code(), 6);
+    }
+
+    #[test]
+    fn line_columns() {
+        assert_eq!(line_column("a = 1", 0), (1, 1));
+        assert_eq!(line_column("a = 1\nbé = x", 11), (2, 5));
+    }
+}
@@ -1,11 +1,11 @@
-use crate::config;
+use ata::Error;
 use rustyline::DefaultEditor;
 use std::fs;
-use std::fs::File;
-use std::io::Write as _;
+use std::path::Path;
 
 pub fn commands() {
     println!("
+Alt-Enter           Insert a newline instead of sending the prompt
 Ctrl-A, Home        Move cursor to the beginning of line
 Ctrl-B, Left        Move cursor one character left
 Ctrl-E, End         Move cursor to end of line
@@ -16,6 +16,7 @@ Ctrl-K              Delete from cursor to end of line
 Ctrl-L              Clear screen
 Ctrl-N, Down        Next match from history
 Ctrl-P, Up          Previous match from history
+Ctrl-X Ctrl-E       Write the prompt in $VISUAL or $EDITOR
 Ctrl-X Ctrl-U       Undo
 Ctrl-Y              Paste from Yank buffer (Meta-Y to paste next yank instead)
 Meta-<              Move to first entry in history
@@ -27,6 +28,7 @@ Meta-F, Alt-Right   Move cursor to next word
 Meta-L              Lower-case the next word
 Meta-T              Transpose words
 Meta-U              Upper-case the next word
+Meta-W              Copy the last response to the clipboard
 Meta-Y              See Ctrl-Y
 Meta-Backspace      Kill from the start of the current word, or, if between words, to the start of the previous word
 Meta-0, 1, ..., -   Specify the digit to the argument. – starts a negative argument.
@@ -35,15 +37,26 @@ Thanks to <https://github.com/kkawakam/rustyline#emacs-mode-default-mode>.
     ");
 }
 
-const EXAMPLE_TOML: &str = r#"api_key = "<YOUR SECRET API KEY>"
-model = "gpt-4-turbo-preview"
+const EXAMPLE_TOML: &str = r#"model = "gpt-4-turbo-preview"
 max_tokens = 2048
 temperature = 0.8"#;
 
-pub fn missing_toml(args: Vec<String>) {
-    // At this point the old organization name is not used so we can use the new one.
-    let old_org = false;
-    let default_path = config::default_path(None, old_org);
+/// The example configuration, with a placeholder for the API key
+/// unless it is already set in the environment.
+fn example_toml() -> String {
+    let has_env_key = ["ATA_API_KEY", "OPENAI_API_KEY"]
+        .iter()
+        .any(|name| std::env::var(name).is_ok_and(|key| !key.trim().is_empty()));
+    if has_env_key {
+        EXAMPLE_TOML.to_string()
+    } else {
+        format!("api_key = \"<YOUR SECRET API KEY>\"\n{EXAMPLE_TOML}")
+    }
+}
+
+/// Explain how to create the configuration at `default_path` and offer to write an example.
+pub fn missing_toml(args: Vec<String>, default_path: &Path) {
+    let example = example_toml();
     eprintln!(
         r#"
 Could not find a configuration file.
@@ -51,11 +64,12 @@ Could not find a configuration file.
 To fix this, use `{} --config=<Path to ata.toml>` or create `{1}`. For the last option, type `y` to write the following example file:
 
 ```
-{EXAMPLE_TOML}
+{example}
 ```
 
 Next, replace `<YOUR SECRET API KEY>` with your API key, which you can request via https://platform.openai.com/api-keys.
 For key permissions, select "Restricted" and select write only for "Model capabilities".
+To keep the key out of this file, remove the `api_key` line and set the `OPENAI_API_KEY` environment variable, `api_key_cmd = "pass show openai"` or `api_key_file = "<Path to the key>"` instead.
 
 The `max_tokens` sets the maximum amount of tokens that the server can answer with.
 Longer answers will be truncated.
@@ -82,13 +96,18 @@ The `temperature` sets the `sampling temperature`. From the OpenAI API docs: "Wh
             .map(|c| c.to_lowercase().collect::<String>() == "y")
             .unwrap_or(false);
         if response {
-            if !default_path.exists() && !default_path.parent().unwrap().is_dir() {
-                let dir = default_path.parent().unwrap();
-                fs::create_dir_all(dir).expect("Could not make configuration directory");
+            if let Some(dir) = default_path.parent() {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    let error = Error::io("create the configuration directory", dir, &e);
+                    eprintln!("\x1b[1mError: \x1b[0m \n{error}");
+                    std::process::exit(error.exit_code());
+                }
+            }
+            if let Err(e) = fs::write(default_path, &example) {
+                let error = Error::io("write", default_path, &e);
+                eprintln!("\x1b[1mError: \x1b[0m \n{error}");
+                std::process::exit(error.exit_code());
             }
-            let mut f = File::create(&default_path).expect("Unable to create file");
-            f.write_all(EXAMPLE_TOML.as_bytes())
-                .expect("Unable to write to file");
             println!();
             println!("Wrote to {default_path:?}.");
         }
@@ -0,0 +1,169 @@
+//! Images that are sent along with a prompt to models that can see.
+
+use crate::attach::format_size;
+use ata::config::Config;
+use ata::Image;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use image::ImageFormat;
+use image::ImageReader;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Extensions of images that the APIs do not accept.
+const UNSUPPORTED: &[&str] = &["gif", "bmp", "tif", "tiff", "heic", "heif", "avif", "svg"];
+
+/// The format of the image in `contents` based on its first bytes.
+fn format(contents: &[u8]) -> Option<ImageFormat> {
+    if contents.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if contents.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(ImageFormat::Jpeg)
+    } else if contents.len() > 12 && &contents[..4] == b"RIFF" && &contents[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else {
+        None
+    }
+}
+
+/// Whether the file at `path` looks like an image that should not be sent as text.
+pub fn is_image_path(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    ["png", "jpg", "jpeg", "webp"].contains(&extension.as_str())
+        || UNSUPPORTED.contains(&extension.as_str())
+}
+
+/// An image that was checked and is ready to be sent.
+#[derive(Debug, PartialEq)]
+pub struct Loaded {
+    pub image: Image,
+    /// Such as `1200x800, 85.0 KB`.
+    pub description: String,
+}
+
+fn downscale(
+    contents: &[u8],
+    format: ImageFormat,
+    max: u32,
+) -> Result<(Vec<u8>, ImageFormat), String> {
+    let image = image::load_from_memory_with_format(contents, format)
+        .map_err(|e| format!("could not read the image ({e})"))?;
+    let image = image.resize(max, max, FilterType::Triangle);
+    let mut out = vec![];
+    let result = if format == ImageFormat::Jpeg {
+        let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+        rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut out, 85))
+    } else {
+        // The WebP encoder is lossless only, so PNG is just as good.
+        image.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+    };
+    result.map_err(|e| format!("could not downscale the image ({e})"))?;
+    let format = if format == ImageFormat::Jpeg {
+        ImageFormat::Jpeg
+    } else {
+        ImageFormat::Png
+    };
+    Ok((out, format))
+}
+
+/// Check the image in `contents` and downscale it if `max_image_dimension` is set.
+pub fn load(contents: Vec<u8>, config: &Config) -> Result<Loaded, String> {
+    let format = format(&contents).ok_or("not a PNG, JPEG or WebP image")?;
+    let dimensions = |contents: &[u8]| {
+        ImageReader::with_format(Cursor::new(contents), format)
+            .into_dimensions()
+            .map_err(|e| format!("could not read the image ({e})"))
+    };
+    let (mut width, mut height) = dimensions(&contents)?;
+    let mut contents = contents;
+    let mut format = format;
+    let mut downscaled = "";
+    if let Some(max) = config.max_image_dimension {
+        if max < width.max(height) {
+            (contents, format) = downscale(&contents, format, max)?;
+            (width, height) = dimensions(&contents)?;
+            downscaled = ", downscaled";
+        }
+    }
+    let size = contents.len() as u64;
+    if config.max_image_bytes < size {
+        let mut reason = format!(
+            "{} is over the limit of {} from `max_image_bytes`",
+            format_size(size),
+            format_size(config.max_image_bytes)
+        );
+        if config.max_image_dimension.is_none() {
+            reason.push_str("; set `max_image_dimension` to downscale large images");
+        }
+        return Err(reason);
+    }
+    let image = Image {
+        media_type: format.to_mime_type().to_string(),
+        data: BASE64.encode(&contents),
+    };
+    let description = format!("{width}x{height}, {}{downscaled}", format_size(size));
+    Ok(Loaded { image, description })
+}
+
+/// Read and check the image at `path`.
+pub fn load_file(path: &Path, config: &Config) -> Result<Loaded, String> {
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if UNSUPPORTED.contains(&extension.as_str()) {
+        return Err(format!(
+            "`{extension}` images are not supported, use PNG, JPEG or WebP"
+        ));
+    }
+    load(contents, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use image::RgbImage;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        let mut out = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn images_are_checked() {
+        let loaded = load(png(40, 20), &test_config("")).unwrap();
+        assert_eq!(loaded.image.media_type, "image/png");
+        assert!(loaded.image.data.starts_with("iVBORw0KGgo"));
+        assert!(loaded.description.starts_with("40x20, "));
+
+        let error = load(b"GIF89a".to_vec(), &test_config("")).unwrap_err();
+        assert_eq!(error, "not a PNG, JPEG or WebP image");
+
+        let error = load(png(40, 20), &test_config("max_image_bytes = 10")).unwrap_err();
+        assert!(error.contains("over the limit of 10 B"));
+        assert!(error.contains("max_image_dimension"));
+    }
+
+    #[test]
+    fn large_images_are_downscaled() {
+        let loaded = load(png(40, 20), &test_config("max_image_dimension = 10")).unwrap();
+        assert!(loaded.description.starts_with("10x5, "));
+        assert!(loaded.description.ends_with(", downscaled"));
+
+        let loaded = load(png(8, 4), &test_config("max_image_dimension = 10")).unwrap();
+        assert!(loaded.description.starts_with("8x4, "));
+    }
+}
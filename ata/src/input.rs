@@ -0,0 +1,110 @@
+//! Multi-line prompts in the REPL.
+//!
+//! Enter sends the prompt unless a fenced code block is still open or the last
+//! line ends with a backslash. Alt-Enter always inserts a newline.
+
+use crate::markdown::is_closing_fence;
+use crate::markdown::opening_fence;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::ValidationContext;
+use rustyline::validate::ValidationResult;
+use rustyline::validate::Validator;
+use rustyline::Helper;
+
+/// For each line in `input`, whether it is part of a fenced code block, including the fences.
+/// Also returns whether the last code block is still open.
+fn in_code(input: &str) -> (Vec<(&str, bool)>, bool) {
+    let mut fence: Option<&str> = None;
+    let mut lines = vec![];
+    for line in input.lines() {
+        match fence {
+            Some(open) => {
+                if is_closing_fence(line, open) {
+                    fence = None;
+                }
+                lines.push((line, true));
+            }
+            None => {
+                fence = opening_fence(line).map(|(_, fence)| fence);
+                lines.push((line, fence.is_some()));
+            }
+        }
+    }
+    (lines, fence.is_some())
+}
+
+/// Whether the user is still typing the prompt after pressing Enter.
+fn is_incomplete(input: &str) -> bool {
+    let (lines, is_open) = in_code(input);
+    is_open || lines.last().is_some_and(|(line, _)| line.ends_with('\\'))
+}
+
+/// The prompt without the backslashes that were used to continue on the next line.
+/// Backslashes in code blocks are kept.
+pub fn join_continued_lines(input: &str) -> String {
+    let (lines, _) = in_code(input);
+    let count = lines.len();
+    let mut joined = String::new();
+    for (i, (line, code)) in lines.into_iter().enumerate() {
+        let is_last = i + 1 == count;
+        match line.strip_suffix('\\') {
+            Some(start) if !code && !is_last => joined.push_str(start),
+            _ => joined.push_str(line),
+        }
+        if !is_last {
+            joined.push('\n');
+        }
+    }
+    joined
+}
+
+/// The rustyline helper which decides when a prompt is complete.
+pub struct InputHelper;
+
+impl Validator for InputHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Completer for InputHelper {
+    type Candidate = String;
+}
+
+impl Hinter for InputHelper {
+    type Hint = String;
+}
+
+impl Highlighter for InputHelper {}
+
+impl Helper for InputHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_input() {
+        assert!(!is_incomplete(""));
+        assert!(!is_incomplete("What is Rust?"));
+        assert!(is_incomplete("Explain this:\n```rust\nfn main() {}"));
+        assert!(!is_incomplete("Explain this:\n```rust\nfn main() {}\n```"));
+        assert!(is_incomplete("~~~~\n~~~"));
+        assert!(is_incomplete("First line\\"));
+        assert!(!is_incomplete("First line\\\nsecond line"));
+    }
+
+    #[test]
+    fn continued_lines_are_joined() {
+        assert_eq!(join_continued_lines("One\\\ntwo"), "One\ntwo");
+        assert_eq!(join_continued_lines("A\\"), "A\\");
+        let code = "```sh\ncargo build \\\n  --release\n```";
+        assert_eq!(join_continued_lines(code), code);
+    }
+}
@@ -0,0 +1,44 @@
+//! Ask the Terminal Anything (ATA) as a library.
+//!
+//! The library resolves and reads the ata configuration and streams responses
+//! from the configured provider. It never prints anything nor exits, so it can
+//! be used by other tools. The `ata` binary is built on top of it.
+//!
+//! ```no_run
+//! use ata::config::Config;
+//! use ata::config::ConfigLocation;
+//! use ata::Client;
+//! use ata::Event;
+//! use ata::Message;
+//! use futures_util::StreamExt;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let config = Config::load(&ConfigLocation::Auto)?;
+//! let client = Client::new(config)?;
+//! let mut events = client.chat(&[Message::user("What is the capital of France?")]);
+//! while let Some(event) = events.next().await {
+//!     if let Event::Text(text) = event? {
+//!         print!("{text}");
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backend;
+mod client;
+pub mod config;
+pub mod error;
+pub mod retry;
+mod sse;
+
+pub use backend::ChatRequest;
+pub use backend::Event;
+pub use backend::EventStream;
+pub use backend::Image;
+pub use backend::Message;
+pub use backend::Role;
+pub use backend::Usage;
+pub use client::Client;
+pub use error::Error;
+pub use error::Result;
@@ -1,24 +1,51 @@
-mod config;
+mod attach;
+mod clipboard;
+mod commands;
+mod editor;
 mod help;
+mod images;
+mod input;
+mod markdown;
 mod prompt;
+mod session;
+mod usage;
 
-use crate::config::Config;
-use crate::config::ConfigLocation;
+use crate::commands::Effect;
+use crate::input::InputHelper;
 use crate::prompt::print_error;
 use crate::prompt::print_prompt;
+use crate::prompt::Outcome;
+use crate::session::Session;
+use crate::usage::Tally;
+use ata::config;
+use ata::config::Config;
+use ata::config::ConfigLocation;
+use ata::config::Provider;
+use ata::Client;
+use ata::Error;
+use ata::Message;
+use ata::Role;
+use ata::Usage;
+use clap::error::ErrorKind;
+use clap::CommandFactory;
 use clap::Parser;
 use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
 use rustyline::Cmd;
 use rustyline::ConditionalEventHandler;
-use rustyline::DefaultEditor;
+use rustyline::Editor;
 use rustyline::Event;
 use rustyline::EventContext;
 use rustyline::EventHandler;
+use rustyline::KeyCode;
 use rustyline::KeyEvent;
+use rustyline::Modifiers;
 use rustyline::RepeatCount;
 use std::env;
-use std::fs::File;
+use std::fs;
+use std::io::IsTerminal;
 use std::io::Read;
+use std::path::Path;
 use std::result::Result;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
@@ -26,14 +53,23 @@ use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
-use toml::from_str;
 
 /// Ask the Terminal Anything (ATA): OpenAI GPT in the terminal
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES)]
 struct Flags {
+    /// Send this prompt, print the response and exit.
+    /// Use `-p` to append the text piped into stdin.
+    #[arg(value_name = "PROMPT")]
+    words: Vec<String>,
+
+    /// Same as the positional prompt, but text piped into stdin is appended to it.
+    #[arg(short = 'p', long = "prompt", conflicts_with = "words")]
+    prompt: Option<String>,
+
     /// Path to the configuration TOML file.
     #[arg(short = 'c', long = "config", default_value = "default")]
     config: ConfigLocation,
@@ -49,6 +85,58 @@ struct Flags {
     /// Print the default config location.
     #[arg(long)]
     print_default_config_location: bool,
+
+    /// Send the previous prompts and responses along with each prompt.
+    /// Type `/clear` to start a new conversation.
+    #[arg(long)]
+    conversation: bool,
+
+    /// Print the response as it is instead of rendering the Markdown.
+    #[arg(long)]
+    raw: bool,
+
+    /// Use the system prompt with this name from the `[personas]` table.
+    #[arg(long, value_name = "NAME")]
+    persona: Option<String>,
+
+    /// Continue a stored session, or the most recent one if no ID is given.
+    #[arg(long, value_name = "ID")]
+    resume: Option<Option<String>>,
+
+    /// Print the stored sessions.
+    #[arg(long)]
+    list_sessions: bool,
+
+    /// Print the models that the server provides, such as the models installed in Ollama.
+    #[arg(long)]
+    list_models: bool,
+
+    /// Print the number of tokens and the cost of all responses at exit.
+    /// Implies `show_usage = true`.
+    #[arg(long)]
+    usage: bool,
+}
+
+const EXIT_CODES: &str = "\
+Exit codes when a prompt is passed as argument or via stdin:
+  0  The response was printed or the reader of stdout stopped, such as `head`
+  1  Other errors such as an unreadable stdin
+  2  Invalid command line arguments
+  3  The API responded with an error
+  4  The API could not be reached or the connection broke
+  5  The response could not be understood
+  6  The configuration is missing or invalid
+  7  A file could not be read or written";
+
+const EXIT_OTHER_ERROR: i32 = 1;
+
+/// Messages from the readline loop to the thread that does the requests.
+enum Input {
+    Prompt(Message),
+    /// Forget the conversation history.
+    Clear,
+    /// Use these settings from now on.
+    Config(Box<Config>),
 }
 
 struct ClearEventHandler;
@@ -62,53 +150,283 @@ impl ConditionalEventHandler for ClearEventHandler {
     }
 }
 
+/// Copies the last response to the clipboard.
+struct CopyEventHandler {
+    last_response: Arc<Mutex<String>>,
+}
+impl ConditionalEventHandler for CopyEventHandler {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        let response = self.last_response.lock().unwrap();
+        if !response.is_empty() {
+            clipboard::copy(&response);
+        }
+        Some(Cmd::Noop)
+    }
+}
+
+/// Stops reading the line so that the main loop opens the editor with it.
+struct EditEventHandler {
+    pending_edit: Arc<Mutex<Option<String>>>,
+}
+impl ConditionalEventHandler for EditEventHandler {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        *self.pending_edit.lock().unwrap() = Some(ctx.line().to_string());
+        Some(Cmd::Interrupt)
+    }
+}
+
+/// Let the user write the prompt in the editor, starting from `initial` or the last prompt.
+/// Returns `None` if nothing should be sent.
+fn edit_prompt(rl: &Editor<InputHelper, DefaultHistory>, initial: &str) -> Option<String> {
+    let last_prompt = rl
+        .history()
+        .iter()
+        .rev()
+        .find(|line| commands::parse(line).is_none());
+    let initial = match last_prompt {
+        Some(last_prompt) if initial.is_empty() => last_prompt.as_str(),
+        _ => initial,
+    };
+    match editor::compose(initial) {
+        Ok(text) if text.trim().is_empty() => {
+            println!("\nThe prompt is empty, so nothing was sent.");
+            None
+        }
+        Ok(text) => {
+            println!("{text}");
+            Some(text)
+        }
+        Err(e) => {
+            println!("\n{e}");
+            None
+        }
+    }
+}
+
 static HAD_FIRST_INTERRUPT: AtomicBool = AtomicBool::new(false);
 
-fn main() -> prompt::TokioResult<()> {
+/// Append the new prompt to the history file so that it survives crashes too.
+fn save_history(rl: &mut Editor<InputHelper, DefaultHistory>, path: &Path) {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = rl.append_history(path) {
+        eprintln!("Could not save the prompt history to {path:?}: {e}");
+    }
+}
+
+/// A configuration for the tests with the required fields and the `extra` lines.
+#[cfg(test)]
+fn test_config(extra: &str) -> Config {
+    format!("model = \"gpt-4\"\nmax_tokens = 10\ntemperature = 0.8\n{extra}")
+        .parse()
+        .unwrap()
+}
+
+/// Print the error and exit with the code for its kind.
+fn exit_with_error(error: &Error) -> ! {
+    if prompt::is_interactive() {
+        eprintln!("\x1b[1mError: \x1b[0m \n{error}");
+    } else {
+        eprintln!("Error: {error}");
+    }
+    std::process::exit(error.exit_code());
+}
+
+/// Send the messages and retry according to the `[retry]` configuration.
+fn request_with_retries(
+    abort: Arc<AtomicBool>,
+    is_running: Arc<AtomicBool>,
+    client: &Client,
+    messages: &[Message],
+    response: &mut String,
+) -> ata::Result<Option<Usage>> {
+    let mut attempt = 1;
+    loop {
+        response.clear();
+        let outcome = prompt::request(
+            abort.clone(),
+            is_running.clone(),
+            client,
+            messages,
+            attempt,
+            response,
+        )?;
+        if let Outcome::Done(usage) = outcome {
+            return Ok(usage);
+        }
+        attempt += 1;
+    }
+}
+
+/// The prompt from the command line arguments and the text from stdin, if any.
+fn non_interactive_prompt(flags: &Flags) -> Option<(String, String)> {
+    // Reading stdin would hang if it is a pipe that stays open, such as under cron.
+    if flags.prompt.is_none() && !flags.words.is_empty() {
+        return Some((flags.words.join(" "), String::new()));
+    }
+    let prompt = flags.prompt.clone();
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return prompt.map(|prompt| (prompt, String::new()));
+    }
+    let mut input = String::new();
+    if let Err(e) = stdin.lock().read_to_string(&mut input) {
+        eprintln!("Error: Could not read stdin: {e}");
+        std::process::exit(EXIT_OTHER_ERROR);
+    }
+    let input = input.trim_end().to_string();
+    match prompt {
+        None if input.is_empty() => None,
+        prompt => Some((prompt.unwrap_or_default(), input)),
+    }
+}
+
+/// Expand the `@path` references in the prompt, add the `piped` text and print which
+/// files are attached.
+fn attach_files(prompt: &str, piped: &str, config: &Config) -> Message {
+    let base = env::current_dir().unwrap_or_default();
+    let (text, attachments) = attach::expand_with_piped(prompt, piped, &base, config);
+    if let Some(preview) = attachments.preview() {
+        if prompt::is_interactive() {
+            println!("\x1b[2m{preview}\x1b[0m");
+        } else {
+            eprintln!("{preview}");
+        }
+    }
+    let images = attachments
+        .images
+        .into_iter()
+        .map(|(_, loaded)| loaded.image)
+        .collect();
+    Message::user(&text).with_images(images)
+}
+
+/// Send one prompt, print the response to stdout and return the exit code.
+fn run_once(client: &Client, message: Message, tally: &mut Tally) -> i32 {
+    let abort = Arc::new(AtomicBool::new(false));
+    let is_running = Arc::new(AtomicBool::new(false));
+    let messages = vec![message];
+    let mut response = String::new();
+    let is_running_clone = is_running.clone();
+    match request_with_retries(abort, is_running_clone, client, &messages, &mut response) {
+        Ok(usage) => {
+            if let Some(usage) = usage {
+                tally.add(&usage, client.config());
+            }
+            0
+        }
+        Err(e) => {
+            print_error(is_running, &e.to_string());
+            e.exit_code()
+        }
+    }
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
     let flags: Flags = Flags::parse();
     if flags.print_shortcuts {
         help::commands();
-        return Ok(());
+        return;
     }
     if flags.print_default_config_location {
         let old_org = false;
-        let default_path = config::default_path(None, old_org);
+        let default_path =
+            config::default_path(None, old_org).unwrap_or_else(|e| exit_with_error(&e));
         println!("{default_path:?}");
-        return Ok(());
+        return;
     }
-    let old_filename = flags.config.location(true);
-    let filename = flags.config.location(false);
-    println!("Ask the Terminal Anything");
-    if !old_filename.exists() && !filename.exists() {
-        help::missing_toml(args);
+    if flags.list_sessions {
+        for summary in session::list() {
+            let id = summary.id;
+            let time = summary.time;
+            let prompts = summary.prompts;
+            let mut first_prompt = summary.first_prompt;
+            if first_prompt.chars().count() > 60 {
+                first_prompt = first_prompt.chars().take(57).collect::<String>() + "...";
+            }
+            println!("{id}  {time}  {prompts:>3} prompts  {first_prompt}");
+        }
+        return;
     }
-    let filename = if old_filename.exists() {
-        old_filename
+    let one_shot = if flags.list_models {
+        None
     } else {
-        filename
+        non_interactive_prompt(&flags)
     };
-    let mut contents = String::new();
-    File::open(filename)
-        .unwrap()
-        .read_to_string(&mut contents)
-        .unwrap();
-
-    let config: Config = from_str(&contents).unwrap();
+    let repl = one_shot.is_none() && !flags.list_models;
+    if one_shot.is_some() && (flags.conversation || flags.resume.is_some()) {
+        Flags::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "`--conversation` and `--resume` only work without a prompt argument or stdin",
+            )
+            .exit();
+    }
+    if !repl {
+        prompt::INTERACTIVE.store(false, Ordering::Relaxed);
+    }
+    if repl {
+        println!("Ask the Terminal Anything");
+    }
+    let path = flags.config.path().unwrap_or_else(|e| exit_with_error(&e));
+    if !path.exists() && repl {
+        // At this point the old organization name is not used so we can use the new one.
+        let old_org = false;
+        match config::default_path(None, old_org) {
+            Ok(default_path) => help::missing_toml(args, &default_path),
+            Err(e) => exit_with_error(&e),
+        }
+    }
+    let mut config = Config::load(&flags.config).unwrap_or_else(|e| exit_with_error(&e));
+    if let Some(name) = flags.persona.clone().or(config.persona.clone()) {
+        if let Err(e) = config.set_persona(Some(&name)) {
+            exit_with_error(&e);
+        }
+    }
+    // The tokens are only counted if the server is asked to send them.
+    if flags.usage {
+        config.show_usage = true;
+    }
+    // Escape codes would end up in files or other programs.
+    if flags.raw || !std::io::stdout().is_terminal() {
+        config.markdown = false;
+    }
+    let mut client = Client::new(config.clone()).unwrap_or_else(|e| exit_with_error(&e));
 
     let model = config.clone().model;
     let max_tokens = config.max_tokens;
     let temperature = config.temperature;
 
-    if !flags.hide_config {
+    if flags.list_models {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime.block_on(client.models()) {
+            Ok(models) => {
+                for model in models {
+                    println!("{model}");
+                }
+                return;
+            }
+            Err(e) => exit_with_error(&e),
+        }
+    }
+
+    if !flags.hide_config && repl {
         println!();
+        if config.provider != Provider::OpenAI {
+            println!("provider: {}", config.provider);
+        }
         println!("model: {model}");
         println!("max_tokens: {max_tokens}");
         println!("temperature: {temperature}");
+        if let Some(persona) = &config.persona {
+            println!("persona: {persona}");
+        }
         println!();
     }
 
-    if model.contains("text") {
+    if config.provider == Provider::OpenAI && model.contains("text") {
         eprintln!(
             "\x1b[1mWARNING:\x1b[0m\n\
             It looks like you are using a text completion model.\n\
@@ -119,46 +437,150 @@ fn main() -> prompt::TokioResult<()> {
         );
     }
 
-    let mut rl = DefaultEditor::new().unwrap();
+    if let Some((prompt, piped)) = one_shot {
+        let mut tally = Tally::default();
+        let message = attach_files(&prompt, &piped, &config);
+        let code = run_once(&client, message, &mut tally);
+        if flags.usage {
+            eprintln!("{}", tally.summary());
+        }
+        std::process::exit(code);
+    }
+
+    let rl_config = rustyline::Config::builder()
+        .max_history_size(config.history_size)
+        .unwrap()
+        .history_ignore_dups(config.history_ignore_dups)
+        .unwrap()
+        // A pasted text is one prompt, even if it contains newlines.
+        .bracketed_paste(true)
+        .build();
+    let mut rl = Editor::with_config(rl_config).unwrap();
+    rl.set_helper(Some(InputHelper));
+    let history_path = config::history_path().unwrap_or_else(|e| exit_with_error(&e));
+    let keep_history = config.save_history;
+    if keep_history && history_path.exists() {
+        if let Err(e) = rl.load_history(&history_path) {
+            eprintln!("Could not load the prompt history from {history_path:?}: {e}");
+        }
+    }
 
     let clear_handler = EventHandler::Conditional(Box::new(ClearEventHandler));
     rl.bind_sequence(KeyEvent::ctrl('L'), clear_handler);
+    rl.bind_sequence(KeyEvent(KeyCode::Enter, Modifiers::ALT), Cmd::Newline);
+    let pending_edit = Arc::new(Mutex::new(None));
+    // Images from `/image` which are sent with the next prompt.
+    let mut pending_images = vec![];
+    let edit_handler = EventHandler::Conditional(Box::new(EditEventHandler {
+        pending_edit: pending_edit.clone(),
+    }));
+    rl.bind_sequence(
+        Event::KeySeq(vec![KeyEvent::ctrl('X'), KeyEvent::ctrl('E')]),
+        edit_handler,
+    );
+    // Selecting a response with the mouse would mangle the indentation of code.
+    let last_response = Arc::new(Mutex::new(String::new()));
+    let copy_handler = EventHandler::Conditional(Box::new(CopyEventHandler {
+        last_response: last_response.clone(),
+    }));
+    rl.bind_sequence(KeyEvent::alt('w'), copy_handler);
 
-    let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let mut conversation = flags.conversation;
+    let mut history: Vec<Message> = vec![];
+    let mut session = if config.save_sessions {
+        Some(Session::new())
+    } else {
+        None
+    };
+    if let Some(id) = &flags.resume {
+        match Session::resume(id.as_deref()) {
+            Ok((resumed, messages)) => {
+                println!("Resuming session {}.", resumed.id());
+                for message in &messages {
+                    println!();
+                    match message.role {
+                        Role::System => prompt::print_bold("System: "),
+                        Role::User => prompt::print_bold("Prompt: "),
+                        Role::Assistant => prompt::print_bold("Response: "),
+                    }
+                    println!("{}", message.content);
+                }
+                println!();
+                if let Some(last) = messages.iter().rfind(|m| m.role == Role::Assistant) {
+                    *last_response.lock().unwrap() = last.content.clone();
+                }
+                conversation = true;
+                history = messages;
+                if config.save_sessions {
+                    session = Some(resumed);
+                } else {
+                    println!("New prompts are not saved since `save_sessions` is false.\n");
+                }
+            }
+            Err(e) => {
+                eprintln!("\x1b[1mError: \x1b[0m \n{e}");
+                std::process::exit(EXIT_OTHER_ERROR);
+            }
+        }
+    }
+    // The worker thread owns the config, so slash commands change this copy
+    // and send it to the worker.
+    let mut settings = config.clone();
+    let (tx, rx): (Sender<Input>, Receiver<Input>) = mpsc::channel();
     let is_running = Arc::new(AtomicBool::new(false));
     let is_running_clone = is_running.clone();
     let abort = Arc::new(AtomicBool::new(false));
     let abort_clone = abort.clone();
+    let tally = Arc::new(Mutex::new(Tally::default()));
+    let tally_clone = tally.clone();
+    let last_response_clone = last_response.clone();
     thread::spawn(move || {
         let abort = abort_clone.clone();
         let is_running = is_running.clone();
         loop {
-            let msg: Result<String, _> = rx.recv();
-            if let Ok(line) = msg {
-                let mut retry = true;
-                let mut count = 1;
-                while retry {
-                    let result = prompt::request(
-                        abort.clone(),
-                        is_running.clone(),
-                        &config,
-                        line.to_string(),
-                        count,
-                    );
-                    retry = match result {
-                        Ok(retry) => retry,
-                        Err(e) => {
-                            eprintln!();
-                            eprintln!();
-                            let msg = format!("prompt::request failed with: {e}");
-                            print_error(is_running.clone(), &msg);
-                            false
-                        }
-                    };
-                    count += 1;
-                    if retry {
-                        let duration = Duration::from_millis(500);
-                        thread::sleep(duration);
+            let msg: Result<Input, _> = rx.recv();
+            if let Ok(Input::Config(new_config)) = msg {
+                if let Err(e) = client.set_config(*new_config) {
+                    eprintln!("Could not apply the settings: {e}");
+                }
+            } else if let Ok(Input::Clear) = msg {
+                history.clear();
+                if session.is_some() {
+                    session = Some(Session::new());
+                }
+            } else if let Ok(Input::Prompt(message)) = msg {
+                let mut messages = if conversation {
+                    history.clone()
+                } else {
+                    vec![]
+                };
+                messages.push(message);
+                let mut response = String::new();
+                let result = request_with_retries(
+                    abort.clone(),
+                    is_running.clone(),
+                    &client,
+                    &messages,
+                    &mut response,
+                );
+                let succeeded = result.is_ok();
+                match result {
+                    Ok(Some(usage)) => tally_clone.lock().unwrap().add(&usage, client.config()),
+                    Ok(None) => (),
+                    Err(e) => print_error(is_running.clone(), &e.to_string()),
+                }
+                if !response.is_empty() {
+                    *last_response_clone.lock().unwrap() = response.clone();
+                }
+                // Failed requests are not part of the conversation or the transcript.
+                if succeeded && !response.is_empty() {
+                    let response = Message::assistant(&response);
+                    if let Some(session) = &mut session {
+                        session.record(messages.last().unwrap(), &response, client.config());
+                    }
+                    if conversation {
+                        messages.push(response);
+                        history = messages;
                     }
                 }
             }
@@ -172,7 +594,18 @@ fn main() -> prompt::TokioResult<()> {
         // "see" that the prompt is ready again during response printing.
         // Also, the current readline is cleared in some cases by rustyline,
         // so being on a newline is the only way to avoid that.
-        let readline = rl.readline("");
+        let pending = pending_edit.lock().unwrap().take();
+        let readline = match pending {
+            Some(initial) => match edit_prompt(&rl, &initial) {
+                Some(text) => Ok(text),
+                None => {
+                    println!();
+                    prompt::print_prompt();
+                    continue;
+                }
+            },
+            None => rl.readline(""),
+        };
         match readline {
             Ok(line) => {
                 if is_running_clone.load(Ordering::SeqCst) {
@@ -181,10 +614,58 @@ fn main() -> prompt::TokioResult<()> {
                 if line.is_empty() {
                     continue;
                 }
-                let _ = rl.add_history_entry(line.as_str());
-                tx.send(line).unwrap();
+                let added = rl.add_history_entry(line.as_str()).unwrap_or(false);
+                if keep_history && added {
+                    save_history(&mut rl, &history_path);
+                }
+                let line = input::join_continued_lines(&line);
+                if let Some(command) = commands::parse(&line) {
+                    println!();
+                    match command.and_then(|command| commands::execute(command, &mut settings)) {
+                        Ok(Effect::Nothing) => (),
+                        Ok(Effect::ConfigChanged) => {
+                            tx.send(Input::Config(Box::new(settings.clone()))).unwrap();
+                        }
+                        Ok(Effect::Clear) => tx.send(Input::Clear).unwrap(),
+                        Ok(Effect::Image(path)) => match images::load_file(&path, &settings) {
+                            Ok(loaded) => {
+                                println!(
+                                    "Attaching {} ({}) to the next prompt.",
+                                    path.display(),
+                                    loaded.description
+                                );
+                                pending_images.push(loaded.image);
+                            }
+                            Err(e) => println!("Could not attach {}: {e}.", path.display()),
+                        },
+                        Ok(Effect::Edit) => *pending_edit.lock().unwrap() = Some(String::new()),
+                        Ok(Effect::Copy(n)) => {
+                            let response = last_response.lock().unwrap();
+                            match clipboard::selection(&response, n) {
+                                Ok(text) => {
+                                    clipboard::copy(&text);
+                                    match n {
+                                        Some(n) => println!("Copied code block {n}."),
+                                        None => println!("Copied the last response."),
+                                    }
+                                }
+                                Err(e) => println!("{e}"),
+                            }
+                        }
+                        Err(e) => println!("{e}"),
+                    }
+                    println!();
+                    prompt::print_prompt();
+                    continue;
+                }
+                let mut message = attach_files(&line, "", &settings);
+                message.images.append(&mut pending_images);
+                tx.send(Input::Prompt(message)).unwrap();
                 HAD_FIRST_INTERRUPT.store(false, Ordering::Relaxed);
             }
+            Err(ReadlineError::Interrupted) if pending_edit.lock().unwrap().is_some() => {
+                println!();
+            }
             Err(ReadlineError::Interrupted) => {
                 if is_running_clone.load(Ordering::SeqCst) {
                     abort.store(true, Ordering::SeqCst);
@@ -208,5 +689,7 @@ fn main() -> prompt::TokioResult<()> {
             }
         }
     }
-    Ok(())
+    if flags.usage {
+        eprintln!("{}", tally.lock().unwrap().summary());
+    }
 }
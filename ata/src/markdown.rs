@@ -0,0 +1,682 @@
+//! Render Markdown for the terminal while the response is streamed.
+//!
+//! Text is printed as soon as it is clear how its line should be styled.
+//! Only lines which have to be complete before they can be rendered are held
+//! back, namely code block fences, code (for the syntax highlighting),
+//! horizontal rules, and tables (to align the columns).
+
+use std::mem::take;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::Theme;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const STRIKE: &str = "\x1b[9m";
+const CYAN: &str = "\x1b[36m";
+const HEADING: &str = "\x1b[1;35m";
+const HEADING_1: &str = "\x1b[1;4;35m";
+
+const THEME: &str = "base16-ocean.dark";
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME_: OnceLock<Theme> = OnceLock::new();
+    THEME_.get_or_init(|| ThemeSet::load_defaults().themes.remove(THEME).unwrap())
+}
+
+/// Styles within a line such as `**bold**` and `` `code` ``.
+#[derive(Default)]
+struct Inline {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    code: bool,
+    /// A run of `*`, `_`, `~` or `` ` `` whose meaning depends on the next character.
+    markers: String,
+    /// The last printed character.
+    prev: Option<char>,
+    /// Style of the whole line, for example for headings.
+    base: &'static str,
+}
+
+fn is_marker(c: char) -> bool {
+    matches!(c, '*' | '_' | '~' | '`')
+}
+
+impl Inline {
+    fn start_line(&mut self, base: &'static str, out: &mut String) {
+        self.base = base;
+        out.push_str(base);
+    }
+
+    fn push(&mut self, c: char, out: &mut String) {
+        if is_marker(c) && (!self.code || c == '`') {
+            if self.markers.chars().any(|m| m != c) {
+                self.resolve(Some(c), out);
+            }
+            self.markers.push(c);
+            return;
+        }
+        self.resolve(Some(c), out);
+        out.push(c);
+        self.prev = Some(c);
+    }
+
+    /// Reset the styles since emphasis is not continued on the next line.
+    fn end_line(&mut self, out: &mut String) {
+        self.resolve(None, out);
+        let styled = self.bold || self.italic || self.strike || self.code;
+        if styled || !self.base.is_empty() {
+            out.push_str(RESET);
+        }
+        *self = Inline::default();
+    }
+
+    fn apply(&self, out: &mut String) {
+        out.push_str(RESET);
+        out.push_str(self.base);
+        if self.bold {
+            out.push_str(BOLD);
+        }
+        if self.italic {
+            out.push_str(ITALIC);
+        }
+        if self.strike {
+            out.push_str(STRIKE);
+        }
+        if self.code {
+            out.push_str(CYAN);
+        }
+    }
+
+    /// Decide whether the pending markers toggle a style or are literal text.
+    fn resolve(&mut self, next: Option<char>, out: &mut String) {
+        if self.markers.is_empty() {
+            return;
+        }
+        let markers = take(&mut self.markers);
+        let marker = markers.chars().next().unwrap();
+        let n = markers.chars().count();
+        let prev = self.prev;
+        if marker == '`' {
+            self.code = !self.code;
+            self.apply(out);
+            return;
+        }
+        // Like CommonMark, `*` can open after and close before a space only
+        // when it touches text, and `_` is not used for emphasis inside words
+        // such as `snake_case`.
+        let can_open = next.is_some_and(|c| !c.is_whitespace())
+            && (marker != '_' || !prev.is_some_and(|c| c.is_alphanumeric()));
+        let can_close = prev.is_some_and(|c| !c.is_whitespace())
+            && (marker != '_' || !next.is_some_and(|c| c.is_alphanumeric()));
+        let toggle = |on: bool| (on && can_close) || (!on && can_open);
+        let toggled = match (marker, n) {
+            ('~', 2) if toggle(self.strike) => {
+                self.strike = !self.strike;
+                true
+            }
+            ('*' | '_', 1) if toggle(self.italic) => {
+                self.italic = !self.italic;
+                true
+            }
+            ('*' | '_', 2) if toggle(self.bold) => {
+                self.bold = !self.bold;
+                true
+            }
+            ('*' | '_', 3) if toggle(self.bold) && toggle(self.italic) => {
+                self.bold = !self.bold;
+                self.italic = !self.italic;
+                true
+            }
+            _ => false,
+        };
+        if toggled {
+            self.apply(out);
+        } else {
+            out.push_str(&markers);
+            self.prev = Some(marker);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Paragraph,
+    Heading(usize),
+    Bullet,
+    Ordered,
+    Quote,
+    Fence,
+    Table,
+    Rule,
+}
+
+/// Decide what kind of line this is, or return `None` if more text is needed.
+fn decide(line: &str, complete: bool) -> Option<Kind> {
+    let wait = if complete {
+        Some(Kind::Paragraph)
+    } else {
+        None
+    };
+    let trimmed = line.trim_start();
+    let first = match trimmed.chars().next() {
+        Some(c) => c,
+        None => return wait,
+    };
+    let second = trimmed.chars().nth(1);
+    match first {
+        '`' | '~' => {
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                Some(Kind::Fence)
+            } else if trimmed.chars().all(|c| c == first) {
+                wait
+            } else {
+                Some(Kind::Paragraph)
+            }
+        }
+        '|' => Some(Kind::Table),
+        '#' => {
+            let n = trimmed.chars().take_while(|c| *c == '#').count();
+            match trimmed[n..].chars().next() {
+                Some(' ') if n <= 6 => Some(Kind::Heading(n)),
+                None => wait,
+                _ => Some(Kind::Paragraph),
+            }
+        }
+        '-' | '*' | '+' | '_' => {
+            if trimmed.chars().all(|c| c == first || c == ' ') {
+                if !complete {
+                    return None;
+                }
+                let n = trimmed.chars().filter(|c| *c == first).count();
+                if n >= 3 && first != '+' {
+                    return Some(Kind::Rule);
+                }
+            }
+            match second {
+                Some(' ') if first != '_' => Some(Kind::Bullet),
+                _ => Some(Kind::Paragraph),
+            }
+        }
+        '0'..='9' => {
+            let n = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+            let mut rest = trimmed[n..].chars();
+            match (rest.next(), rest.next()) {
+                (Some('.' | ')'), Some(' ')) => Some(Kind::Ordered),
+                (None, _) | (Some('.' | ')'), None) => wait,
+                _ => Some(Kind::Paragraph),
+            }
+        }
+        '>' => Some(Kind::Quote),
+        _ => Some(Kind::Paragraph),
+    }
+}
+
+fn split_row(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    row.split('|').map(|cell| cell.trim().to_string()).collect()
+}
+
+fn is_separator(row: &[String]) -> bool {
+    row.iter().all(|cell| {
+        let dashes = cell.trim_matches(':');
+        !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+    })
+}
+
+/// The number of characters that are visible in the terminal.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in text.chars() {
+        match (in_escape, c) {
+            (false, '\x1b') => in_escape = true,
+            (true, 'm') => in_escape = false,
+            (true, _) => (),
+            (false, _) => width += 1,
+        }
+    }
+    width
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// The indentation and the opening fence of a code block, such as `` ``` `` or `~~~~`, if any.
+pub fn opening_fence(line: &str) -> Option<(usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let trimmed = &line[indent..];
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = trimmed.len() - trimmed.trim_start_matches(marker).len();
+    if length < 3 {
+        return None;
+    }
+    // A backtick fence cannot have backticks in the info string.
+    if marker == '`' && trimmed[length..].contains('`') {
+        return None;
+    }
+    Some((indent, &trimmed[..length]))
+}
+
+/// Whether `line` ends the code block that was opened by `fence`.
+pub fn is_closing_fence(line: &str, fence: &str) -> bool {
+    let trimmed = line.trim();
+    let marker = fence.chars().next().unwrap();
+    trimmed.len() >= fence.len() && trimmed.chars().all(|c| c == marker)
+}
+
+struct CodeBlock {
+    fence: String,
+    highlighter: Option<HighlightLines<'static>>,
+}
+
+impl CodeBlock {
+    fn new(fence_line: &str) -> Self {
+        let trimmed = fence_line.trim_start();
+        let marker = trimmed.chars().next().unwrap();
+        let fence: String = trimmed.chars().take_while(|c| *c == marker).collect();
+        let lang = trimmed[fence.len()..]
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+        let highlighter = if lang.is_empty() {
+            None
+        } else {
+            syntax_set()
+                .find_syntax_by_token(lang)
+                .map(|syntax| HighlightLines::new(syntax, theme()))
+        };
+        CodeBlock { fence, highlighter }
+    }
+
+    fn is_closed_by(&self, line: &str) -> bool {
+        is_closing_fence(line, &self.fence)
+    }
+
+    fn render(&mut self, line: &str, out: &mut String) {
+        let highlighted = self.highlighter.as_mut().and_then(|highlighter| {
+            let line = format!("{line}\n");
+            let ranges = highlighter.highlight_line(&line, syntax_set()).ok()?;
+            Some(as_24_bit_terminal_escaped(&ranges, false))
+        });
+        match highlighted {
+            Some(highlighted) => {
+                out.push_str(highlighted.trim_end_matches('\n'));
+                out.push_str(RESET);
+            }
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Not enough of the line has been received to know how to render it.
+    Undecided,
+    /// The line is printed while it streams in.
+    Inline,
+    /// The line is rendered when it is complete.
+    Whole,
+}
+
+pub struct Renderer {
+    line: String,
+    mode: Mode,
+    inline: Inline,
+    code: Option<CodeBlock>,
+    table: Vec<String>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            line: String::new(),
+            mode: Mode::Undecided,
+            inline: Inline::default(),
+            code: None,
+            table: vec![],
+        }
+    }
+
+    /// Take the next part of the response and return what should be printed.
+    pub fn push(&mut self, text: &str) -> String {
+        let mut out = String::new();
+        for c in text.chars() {
+            match (c, self.mode) {
+                ('\r', _) => (),
+                ('\n', _) => self.end_line(&mut out),
+                (_, Mode::Inline) => self.inline.push(c, &mut out),
+                (_, Mode::Whole) => self.line.push(c),
+                (_, Mode::Undecided) => {
+                    self.line.push(c);
+                    self.classify(&mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// Return what is left to print at the end of the response.
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        if self.mode == Mode::Inline {
+            self.inline.end_line(&mut out);
+        } else if !self.line.is_empty() {
+            let line = take(&mut self.line);
+            self.render_line(&line, &mut out);
+            self.flush_table(&mut out);
+            // The response did not end with a newline.
+            out.pop();
+        }
+        self.flush_table(&mut out);
+        *self = Renderer::new();
+        out
+    }
+
+    fn classify(&mut self, out: &mut String) {
+        if self.code.is_some() {
+            self.mode = Mode::Whole;
+            return;
+        }
+        match decide(&self.line, false) {
+            None => (),
+            Some(Kind::Fence | Kind::Table | Kind::Rule) => self.mode = Mode::Whole,
+            Some(kind) => {
+                let line = take(&mut self.line);
+                self.start_inline(kind, &line, out);
+                self.mode = Mode::Inline;
+            }
+        }
+    }
+
+    fn end_line(&mut self, out: &mut String) {
+        if self.mode == Mode::Inline {
+            self.inline.end_line(out);
+            out.push('\n');
+        } else {
+            let line = take(&mut self.line);
+            self.render_line(&line, out);
+        }
+        self.mode = Mode::Undecided;
+    }
+
+    fn start_inline(&mut self, kind: Kind, line: &str, out: &mut String) {
+        self.flush_table(out);
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let (base, content) = match kind {
+            Kind::Heading(level) => {
+                let base = if level == 1 { HEADING_1 } else { HEADING };
+                (base, trimmed[level + 1..].to_string())
+            }
+            Kind::Bullet => {
+                out.push_str(&format!("{indent}{CYAN}•{RESET} "));
+                ("", trimmed[2..].to_string())
+            }
+            Kind::Ordered => {
+                let (number, content) = trimmed.split_once(' ').unwrap();
+                out.push_str(&format!("{indent}{CYAN}{number}{RESET} "));
+                ("", content.to_string())
+            }
+            Kind::Quote => {
+                out.push_str(&format!("{indent}{DIM}│{RESET}"));
+                (ITALIC, trimmed[1..].to_string())
+            }
+            _ => ("", line.to_string()),
+        };
+        self.inline.start_line(base, out);
+        for c in content.chars() {
+            self.inline.push(c, out);
+        }
+    }
+
+    fn render_line(&mut self, line: &str, out: &mut String) {
+        if let Some(code) = &mut self.code {
+            if code.is_closed_by(line) {
+                out.push_str(&format!("{DIM}{line}{RESET}\n"));
+                self.code = None;
+            } else {
+                code.render(line, out);
+            }
+            return;
+        }
+        match decide(line, true).unwrap() {
+            Kind::Table => self.table.push(line.to_string()),
+            Kind::Fence => {
+                self.flush_table(out);
+                self.code = Some(CodeBlock::new(line));
+                out.push_str(&format!("{DIM}{line}{RESET}\n"));
+            }
+            Kind::Rule => {
+                self.flush_table(out);
+                out.push_str(&format!("{DIM}{}{RESET}\n", "─".repeat(40)));
+            }
+            kind => {
+                self.start_inline(kind, line, out);
+                self.inline.end_line(out);
+                out.push('\n');
+            }
+        }
+    }
+
+    fn render_cell(text: &str, base: &'static str) -> String {
+        let mut inline = Inline::default();
+        let mut out = String::new();
+        inline.start_line(base, &mut out);
+        for c in text.chars() {
+            inline.push(c, &mut out);
+        }
+        inline.end_line(&mut out);
+        out
+    }
+
+    fn flush_table(&mut self, out: &mut String) {
+        if self.table.is_empty() {
+            return;
+        }
+        let rows: Vec<Vec<String>> = take(&mut self.table)
+            .iter()
+            .map(|row| split_row(row))
+            .collect();
+        let has_header = rows.get(1).is_some_and(|row| is_separator(row));
+        let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut aligns = vec![Align::Left; columns];
+        if has_header {
+            for (i, cell) in rows[1].iter().enumerate() {
+                aligns[i] = match (cell.starts_with(':'), cell.ends_with(':')) {
+                    (true, true) => Align::Center,
+                    (false, true) => Align::Right,
+                    _ => Align::Left,
+                };
+            }
+        }
+        let rendered: Vec<Option<Vec<String>>> = rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                if is_separator(row) {
+                    return None;
+                }
+                let base = if has_header && i == 0 { BOLD } else { "" };
+                let mut cells: Vec<String> = row
+                    .iter()
+                    .map(|cell| Self::render_cell(cell, base))
+                    .collect();
+                cells.resize(columns, String::new());
+                Some(cells)
+            })
+            .collect();
+        let mut widths = vec![0; columns];
+        for cells in rendered.iter().flatten() {
+            for (i, cell) in cells.iter().enumerate() {
+                widths[i] = widths[i].max(visible_width(cell));
+            }
+        }
+        for cells in &rendered {
+            let line: Vec<String> = match cells {
+                None => widths.iter().map(|w| "─".repeat(w + 2)).collect(),
+                Some(cells) => cells
+                    .iter()
+                    .enumerate()
+                    .map(|(i, cell)| {
+                        let padding = widths[i] - visible_width(cell);
+                        let (left, right) = match aligns[i] {
+                            Align::Left => (0, padding),
+                            Align::Center => (padding / 2, padding - padding / 2),
+                            Align::Right => (padding, 0),
+                        };
+                        format!(" {}{cell}{} ", " ".repeat(left), " ".repeat(right))
+                    })
+                    .collect(),
+            };
+            let separator = if cells.is_some() {
+                format!("{DIM}│{RESET}")
+            } else {
+                format!("{DIM}┼{RESET}")
+            };
+            let line = line.join(&separator);
+            if cells.is_some() {
+                out.push_str(&line);
+            } else {
+                out.push_str(&format!("{DIM}{line}{RESET}"));
+            }
+            out.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn render(text: &str) -> String {
+        let mut renderer = Renderer::new();
+        let mut out = renderer.push(text);
+        out.push_str(&renderer.finish());
+        out
+    }
+
+    fn strip_escapes(text: &str) -> String {
+        let mut out = String::new();
+        let mut in_escape = false;
+        for c in text.chars() {
+            match (in_escape, c) {
+                (false, '\x1b') => in_escape = true,
+                (true, 'm') => in_escape = false,
+                (true, _) => (),
+                (false, _) => out.push(c),
+            }
+        }
+        out
+    }
+
+    const RESPONSE: &str = "\
+# Title
+
+Some **bold**, *italic*, `code` and snake_case_name.
+- first
+  - nested
+1. one
+> quote
+---
+
+| Name | Value |
+|------|------:|
+| a    | 1     |
+| long | 100   |
+
+```rust
+fn main() {}
+```
+Done";
+
+    #[test]
+    fn chunk_boundaries_do_not_matter() {
+        let expected = render(RESPONSE);
+        let mut renderer = Renderer::new();
+        let mut out = String::new();
+        for c in RESPONSE.chars() {
+            out.push_str(&renderer.push(&c.to_string()));
+        }
+        out.push_str(&renderer.finish());
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn visible_text() {
+        let expected = "\
+Title
+
+Some bold, italic, code and snake_case_name.
+• first
+  • nested
+1. one
+│ quote
+────────────────────────────────────────
+
+ Name │ Value 
+──────┼───────
+ a    │     1 
+ long │   100 
+
+```rust
+fn main() {}
+```
+Done";
+        assert_eq!(strip_escapes(&render(RESPONSE)), expected);
+    }
+
+    #[test]
+    fn inline_styles() {
+        assert_eq!(
+            render("a **b** *c* ~~d~~"),
+            format!("a {RESET}{BOLD}b{RESET} {RESET}{ITALIC}c{RESET} {RESET}{STRIKE}d{RESET}")
+        );
+        assert_eq!(render("2 * 3 * 4"), "2 * 3 * 4");
+        assert_eq!(render("`a*b*c`"), format!("{RESET}{CYAN}a*b*c{RESET}"));
+    }
+
+    #[test]
+    fn unclosed_styles_are_reset() {
+        assert_eq!(render("**a\nb"), format!("{RESET}{BOLD}a{RESET}\nb"));
+    }
+
+    #[test]
+    fn code_is_highlighted() {
+        let out = render("```rust\nlet x = 1;\n```\n");
+        assert!(out.contains("\x1b[38;2;"), "{out:?}");
+        let out = render("```unknown-language\nlet x = 1;\n```\n");
+        assert_eq!(
+            out,
+            format!("{DIM}```unknown-language{RESET}\nlet x = 1;\n{DIM}```{RESET}\n")
+        );
+    }
+
+    #[test]
+    fn markers_in_code_blocks_are_literal() {
+        let out = render("```\n# not a heading\n**x**\n```");
+        assert_eq!(strip_escapes(&out), "```\n# not a heading\n**x**\n```");
+    }
+}
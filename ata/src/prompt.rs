@@ -1,28 +1,73 @@
-use hyper::body::HttpBody;
-use hyper::Body;
-use hyper::Client;
-use hyper::Method;
-use hyper::Request;
-use hyper_rustls::HttpsConnectorBuilder;
-use serde_json::json;
-use serde_json::Value;
-use std::error::Error;
+use crate::markdown::Renderer;
+use crate::usage;
+use ata::backend::Event;
+use ata::backend::EventStream;
+use ata::backend::Message;
+use ata::backend::Usage;
+use ata::Client;
+use ata::Error;
+use futures_util::StreamExt;
+use std::io;
 use std::io::Write;
-use std::result::Result;
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
-pub type TokioResult<T, E = Box<dyn Error + Send + Sync>> = Result<T, E>;
+/// Whether ata runs as a REPL.
+/// Otherwise, only the response is printed to stdout and everything else to stderr.
+pub static INTERACTIVE: AtomicBool = AtomicBool::new(true);
 
-fn sanitize_input(input: String) -> String {
-    let out = input.trim_end_matches("\n");
-    out.replace('"', "\\\"")
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// A short description of a temporary problem.
+fn retry_reason(error: &Error) -> String {
+    match error {
+        Error::Api {
+            status: Some(status),
+            ..
+        } => format!("Server responded with status {status}"),
+        Error::Api {
+            kind: Some(kind), ..
+        } => format!("Server responded with a `{kind}`"),
+        Error::Http(_) => "The connection failed".to_string(),
+        Error::Timeout(_) => "The server took too long".to_string(),
+        _ => "The request failed".to_string(),
+    }
+}
+
+/// How a request ended if there was no error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The response was printed completely or aborted by the user.
+    /// Contains the number of tokens if the server sent them.
+    Done(Option<Usage>),
+    /// The server had a temporary problem and the delay has passed, so the request
+    /// should be sent again.
+    Retry,
 }
 
 fn print_and_flush(text: &str) {
-    print!("{text}");
-    std::io::stdout().flush().unwrap();
+    // When stdout is a closed pipe, there is nobody left to read this.
+    let _ = write_and_flush(&mut std::io::stdout(), text);
+}
+
+fn write_and_flush<W: Write>(out: &mut W, text: &str) -> io::Result<()> {
+    write!(out, "{text}")?;
+    out.flush()
+}
+
+/// Stop printing the response when writing to `out` failed.
+/// A closed pipe, such as in `ata "hi" | head -2`, means that the reader has enough.
+fn stop_printing(error: io::Error, usage: Option<Usage>) -> Result<Option<Usage>, Error> {
+    if error.kind() == io::ErrorKind::BrokenPipe {
+        Ok(usage)
+    } else {
+        Err(Error::io("write to", Path::new("stdout"), &error))
+    }
 }
 
 pub fn print_bold(msg: &str) {
@@ -33,19 +78,24 @@ pub fn print_prompt() {
     print_bold("Prompt: ");
 }
 
-fn print_response() {
-    print_bold("Response: ");
-}
-
 fn finish_prompt(is_running: Arc<AtomicBool>) {
     is_running.store(false, Ordering::SeqCst);
-    print_and_flush("\n\n");
-    print_prompt();
+    if is_interactive() {
+        print_and_flush("\n\n");
+        print_prompt();
+    } else {
+        print_and_flush("\n");
+    }
 }
 
 pub fn print_error(is_running: Arc<AtomicBool>, msg: &str) {
-    eprintln!("\x1b[1mError: \x1b[0m \n{msg}");
-    finish_prompt(is_running)
+    if is_interactive() {
+        eprintln!("\x1b[1mError: \x1b[0m \n{msg}");
+        finish_prompt(is_running)
+    } else {
+        is_running.store(false, Ordering::SeqCst);
+        eprintln!("Error: {msg}");
+    }
 }
 
 fn store_and_do_nothing(print_buffer: &mut Vec<String>, text: &str) -> String {
@@ -77,203 +127,292 @@ fn post_process(print_buffer: &mut Vec<String>, text: &str) -> String {
     fix_newlines(print_buffer, text)
 }
 
-fn value2unquoted_text(value: &serde_json::Value) -> String {
-    value.as_str().unwrap().to_string()
+/// Wait until the user aborts the response.
+async fn aborted(abort: &AtomicBool) {
+    while !abort.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
 }
 
-fn should_retry(line: &str, count: i64) -> bool {
-    let v: Value = match serde_json::from_str(line) {
-        Ok(line) => line,
-        Err(_) => return false,
+/// Wait for `delay` unless the user aborts first.
+/// Returns whether the delay passed.
+async fn backoff(delay: Duration, abort: &AtomicBool) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = aborted(abort) => {
+            abort.store(false, Ordering::SeqCst);
+            false
+        }
+    }
+}
+
+/// Print the text in the events to `out` until the stream ends or the user aborts.
+/// Returns the error that ended the stream, if any.
+async fn print_events<W: Write>(
+    events: &mut EventStream,
+    abort: &AtomicBool,
+    markdown: bool,
+    out: &mut W,
+    response_text: &mut String,
+) -> Result<Option<Usage>, Error> {
+    let mut had_first_event = false;
+    let mut usage: Option<Usage> = None;
+    let mut had_first_success = false;
+    let mut print_buffer: Vec<String> = vec![];
+    let mut renderer = if markdown {
+        Some(Renderer::new())
+    } else {
+        None
     };
-    if v.get("error").is_some() {
-        let error_type = value2unquoted_text(&v["error"]["type"]);
-        let max_tries = 3;
-        if count < max_tries && error_type == "server_error" {
-            println!(
-                "\
-                Server responded with a `server_error`. \
-                Trying again... ({count}/{max_tries})\
-                "
-            );
-            return true;
+    let result = loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = aborted(abort) => {
+                abort.store(false, Ordering::SeqCst);
+                break Ok(usage);
+            }
+        };
+        let mut text = String::new();
+        // Do not move this in front of the request for UX reasons.
+        if !had_first_event {
+            had_first_event = true;
+            if is_interactive() {
+                text.push('\n');
+            }
+        }
+        match event {
+            // The first response is (sometimes?) empty.
+            Some(Ok(Event::Text(part))) if part.is_empty() => (),
+            Some(Ok(Event::Text(part))) => {
+                let processed = post_process(&mut print_buffer, &part);
+                if !had_first_success {
+                    had_first_success = true;
+                    if is_interactive() {
+                        text.push_str("\x1b[1mResponse: \x1b[0m\n");
+                    }
+                }
+                match &mut renderer {
+                    Some(renderer) => text.push_str(&renderer.push(&processed)),
+                    None => text.push_str(&processed),
+                }
+                response_text.push_str(&processed);
+            }
+            Some(Ok(Event::Usage(part))) => *usage.get_or_insert_with(Usage::default) += part,
+            Some(Ok(Event::Finish(_))) => (),
+            Some(Err(e)) => break Err(e),
+            None => break Ok(usage),
+        }
+        if let Err(e) = write_and_flush(out, &text) {
+            break stop_printing(e, usage);
+        }
+    };
+    // Print what the Markdown renderer still holds, such as an unfinished table.
+    if let Some(renderer) = &mut renderer {
+        if let Err(e) = write_and_flush(out, &renderer.finish()) {
+            if result.is_ok() {
+                return stop_printing(e, usage);
+            }
         }
     }
-    false
+    result
 }
 
 /// This function is the main entry point for the prompt module.
-/// Returns `true` if the request should be retried.
+/// Sends `messages` after the system prompt, if any, and appends the streamed
+/// answer to `response_text`. Errors are returned for the caller to print.
 #[tokio::main]
 pub async fn request(
     abort: Arc<AtomicBool>,
     is_running: Arc<AtomicBool>,
-    config: &super::Config,
-    prompt: String,
-    count: i64,
-) -> TokioResult<bool> {
+    client: &Client,
+    messages: &[Message],
+    attempt: u32,
+    response_text: &mut String,
+) -> ata::Result<Outcome> {
     is_running.store(true, Ordering::SeqCst);
 
-    let api_key: String = config.clone().api_key;
-    let model: String = config.clone().model;
-    let max_tokens: i64 = config.clone().max_tokens;
-    let temperature: f64 = config.temperature;
-
-    let sanitized_input = sanitize_input(prompt.clone());
-    let bearer = format!("Bearer {api_key}");
-    // Passing newlines behind the prompt to get a more chat-like experience.
-    let body = json!({
-        "model": model,
-        "messages": [
-            {
-                "role": "user",
-                "content": format!("{sanitized_input}\\n\\n")
+    let mut events = client.chat(messages);
+    let mut stdout = std::io::stdout();
+    let result = print_events(
+        &mut events,
+        &abort,
+        client.config().markdown,
+        &mut stdout,
+        response_text,
+    )
+    .await;
+
+    let error = match result {
+        Ok(usage) => {
+            let config = client.config();
+            if let Some(usage) = &usage {
+                if is_interactive() && config.show_usage {
+                    let cost = config.price().map(|price| price.cost(usage));
+                    print_and_flush(&format!(
+                        "\n\n\x1b[2m{}\x1b[0m",
+                        usage::describe(usage, cost)
+                    ));
+                }
             }
-        ],
-        "max_tokens": max_tokens,
-        "temperature": temperature,
-        "stream": true
-    })
-    .to_string();
-
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri("https://api.openai.com/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", bearer)
-        .body(Body::from(body))?;
-
-    let https = HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .https_only()
-        .enable_http1()
-        .build();
-
-    let client = Client::builder().build::<_, hyper::Body>(https);
-
-    let mut response = match client.request(req).await {
-        Ok(response) => response,
-        Err(e) => {
-            print_and_flush("\n");
-            print_error(is_running, &e.to_string());
-            return Ok(false);
+            finish_prompt(is_running);
+            return Ok(Outcome::Done(usage));
         }
+        Err(error) => error,
     };
-
-    // Do not move this in front of the request for UX reasons.
-    print_and_flush("\n");
-
-    let mut had_first_success = false;
-    let mut data_buffer = vec![];
-    let mut print_buffer: Vec<String> = vec![];
-    while let Some(chunk) = response.body_mut().data().await {
-        let chunk = chunk?;
-        data_buffer.extend_from_slice(&chunk);
-
-        let events = std::str::from_utf8(&data_buffer)?.split("\n\n");
-        for line in events {
-            // Cannot use startswith because there are sometimes leading newlines.
-            if line.contains("data:") {
-                let start = match line.find("{") {
-                    Some(start) => start,
-                    None => {
-                        // Response didn't contain JSON, so it's most likely done.
-                        finish_prompt(is_running);
-                        return Ok(false);
-                    }
-                };
-                let data: &str = &line[start..];
-                let v: Value = serde_json::from_str(data)?;
-
-                if v.get("choices").is_some() {
-                    let choices = v.get("choices").unwrap();
-                    // We request only one completion.
-                    let choice: &Value = &choices[0];
-                    // println!("choice: {choice}");
-                    if false {  // choice.get("finish_reason").is_some() {
-                        if choice["finish_reason"] == "stop" {
-                            finish_prompt(is_running);
-                            return Ok(false);
-                        }
-                    }
-                    let delta = choice.get("delta");
-                    if delta.is_none() {
-                        // Ignoring wrong responses to avoid crashes.
-                        continue;
-                    }
-                    let content = delta.unwrap().get("content");
-                    if content.is_none() {
-                        // Probably switching "role" (`"role":"assistant"`).
-                        continue;
-                    }
-                    let content = content.unwrap();
-                    let text = value2unquoted_text(&content);
-                    // The first response is (sometimes?) empty.
-                    if text.is_empty() {
-                        if !had_first_success {
-                            had_first_success = true;
-                        }
-                        continue;
-                    }
-                    let processed = post_process(&mut print_buffer, &text);
-                    if !had_first_success {
-                        had_first_success = true;
-                        print_response();
-                    };
-                    print_and_flush(&processed);
-                } else if v.get("error").is_some() {
-                    let msg = value2unquoted_text(&v["error"]["message"]);
-                    let msg = format!("Received an error message from OpenAI: {msg}");
-                    print_error(is_running, &msg);
-                    return Ok(false);
-                } else {
-                    let msg = format!("Response didn't contain 'choices': {data}");
-                    print_error(is_running, &msg);
-                    return Ok(false);
-                };
-            } else if !line.is_empty() {
-                if !had_first_success {
-                    let retry = should_retry(line, count);
-                    if retry {
-                        return Ok(true);
-                    } else {
-                        let msg = format!("Response didn't contain 'data': {line}");
-                        print_error(is_running, &msg);
-                        return Ok(false);
-                    }
-                };
-                let msg = format!("Response didn't contain 'data:': {line}");
-                print_error(is_running, &msg);
-                return Ok(false);
-            };
-            if abort.load(Ordering::SeqCst) {
-                abort.store(false, Ordering::SeqCst);
-                finish_prompt(is_running);
-                return Ok(false);
-            };
+    let retry = &client.config().retry;
+    if response_text.is_empty() {
+        if let Some(delay) = retry.delay(attempt, &error) {
+            let max_attempts = retry.max_attempts;
+            eprintln!(
+                "{}. Trying again in {:.1} s... ({attempt}/{max_attempts})",
+                retry_reason(&error),
+                delay.as_secs_f64()
+            );
+            if backoff(delay, &abort).await {
+                return Ok(Outcome::Retry);
+            }
+            finish_prompt(is_running);
+            return Ok(Outcome::Done(None));
         }
-        data_buffer.clear();
     }
-    finish_prompt(is_running);
-    Ok(false)
+    Err(error)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ata::backend::mock::MockBackend;
+    use ata::backend::Backend;
+    use ata::backend::ChatRequest;
+    use std::thread;
+
+    fn chat_request() -> ChatRequest {
+        ChatRequest {
+            model: "gpt-4".to_string(),
+            system_prompt: None,
+            messages: vec![Message::user("Hi")],
+            max_tokens: 10,
+            temperature: 0.8,
+            include_usage: false,
+        }
+    }
+
+    async fn print(
+        backend: &MockBackend,
+        abort: &AtomicBool,
+    ) -> (String, String, Result<Option<Usage>, Error>) {
+        let mut events = backend.chat(&chat_request());
+        let mut out = vec![];
+        let mut response_text = String::new();
+        let result = print_events(&mut events, abort, false, &mut out, &mut response_text).await;
+        (String::from_utf8(out).unwrap(), response_text, result)
+    }
+
+    #[tokio::test]
+    async fn events_are_printed() {
+        let backend = MockBackend::texts(&["", "Hello", " wor", "ld\\", "n!"]);
+        let abort = AtomicBool::new(false);
+        let (out, response_text, result) = print(&backend, &abort).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(response_text, "Hello world\n!");
+        assert_eq!(out, "\n\x1b[1mResponse: \x1b[0m\nHello world\n!");
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn usage_parts_are_added() {
+        let usage = |prompt_tokens, completion_tokens| Usage {
+            prompt_tokens,
+            completion_tokens,
+        };
+        let backend = MockBackend::new(vec![
+            Ok(Event::Usage(usage(12, 0))),
+            Ok(Event::Text("Hi".to_string())),
+            Ok(Event::Usage(usage(0, 3))),
+        ]);
+        let abort = AtomicBool::new(false);
+        let (_, _, result) = print(&backend, &abort).await;
+        assert_eq!(result, Ok(Some(usage(12, 3))));
+    }
+
+    #[tokio::test]
+    async fn errors_end_the_stream() {
+        let error = Error::Api {
+            status: None,
+            kind: Some("server_error".to_string()),
+            message: "Oops".to_string(),
+            retry_after: None,
+        };
+        let backend = MockBackend::new(vec![
+            Ok(Event::Text("Hi".to_string())),
+            Err(error.clone()),
+            Ok(Event::Text("never printed".to_string())),
+        ]);
+        let abort = AtomicBool::new(false);
+        let (_, response_text, result) = print(&backend, &abort).await;
+        assert_eq!(result, Err(error));
+        assert_eq!(response_text, "Hi");
+    }
+
+    /// A pipe of which the reader has stopped, such as `head`.
+    struct ClosedPipe(io::ErrorKind);
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(self.0))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_pipes_stop_the_response() {
+        let backend = MockBackend::texts(&["Hello", " world"]).hanging();
+        let mut events = backend.chat(&chat_request());
+        let abort = AtomicBool::new(false);
+        let mut out = ClosedPipe(io::ErrorKind::BrokenPipe);
+        let mut response_text = String::new();
+        let result = print_events(&mut events, &abort, false, &mut out, &mut response_text).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(response_text, "Hello");
+
+        let mut events = backend.chat(&chat_request());
+        let mut out = ClosedPipe(io::ErrorKind::PermissionDenied);
+        let result = print_events(&mut events, &abort, false, &mut out, &mut String::new()).await;
+        assert!(matches!(result, Err(Error::Io { .. })));
+    }
+
+    #[tokio::test]
+    async fn abort_stops_the_backoff() {
+        let abort = Arc::new(AtomicBool::new(false));
+        assert!(backoff(Duration::from_millis(10), &abort).await);
 
-    #[test]
-    fn leading_newlines() {
-        assert_eq!(
-            sanitize_input("foo\"bar".to_string()),
-            "foo\\\"bar".to_string()
-        );
+        let abort_clone = abort.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            abort_clone.store(true, Ordering::SeqCst);
+        });
+        let start = std::time::Instant::now();
+        assert!(!backoff(Duration::from_secs(60), &abort).await);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(!abort.load(Ordering::SeqCst));
     }
 
-    #[test]
-    fn value_is_unquoted() {
-        use super::*;
-        let v: Value = serde_json::from_str(r#"{"a": "1"}"#).unwrap();
-        assert_eq!(value2unquoted_text(&v["a"]), "1");
+    #[tokio::test]
+    async fn abort_stops_a_hanging_stream() {
+        let backend = MockBackend::texts(&["Hi"]).hanging();
+        let abort = Arc::new(AtomicBool::new(false));
+        let abort_clone = abort.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            abort_clone.store(true, Ordering::SeqCst);
+        });
+        let (_, response_text, result) = print(&backend, &abort).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(response_text, "Hi");
+        // The flag is reset for the next response.
+        assert!(!abort.load(Ordering::SeqCst));
     }
 }
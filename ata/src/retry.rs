@@ -0,0 +1,179 @@
+//! When and how long to wait before sending a request again.
+
+use crate::error::Error;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// A condition under which a failed request is sent again.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// Status 429 or a `rate_limit_error`.
+    RateLimit,
+    /// Status 500, 502, 503 or 504 or a `server_error` or `api_error` in the stream.
+    ServerError,
+    /// Status 529 or an `overloaded_error`, which is how Anthropic says that it is busy.
+    Overloaded,
+    /// The server could not be reached or closed the connection.
+    Connection,
+    /// The server did not connect or respond in time, see the `[timeout]` section.
+    Timeout,
+}
+
+/// The `[retry]` section of the configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Retry {
+    /// Number of tries including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second try which doubles for every next try.
+    pub base_delay_ms: u64,
+    /// Longest delay. Give up when the server asks to wait longer.
+    pub max_delay_ms: u64,
+    /// Add up to this fraction of the delay at random so that clients don't retry in lockstep.
+    pub jitter: f64,
+    /// The conditions under which to retry.
+    pub on: Vec<RetryOn>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 60_000,
+            jitter: 0.2,
+            on: vec![
+                RetryOn::RateLimit,
+                RetryOn::ServerError,
+                RetryOn::Overloaded,
+                RetryOn::Connection,
+                RetryOn::Timeout,
+            ],
+        }
+    }
+}
+
+/// The condition that the error falls under, if any.
+fn condition(error: &Error) -> Option<RetryOn> {
+    match error {
+        Error::Http(_) => Some(RetryOn::Connection),
+        Error::Timeout(_) => Some(RetryOn::Timeout),
+        Error::Api { status, kind, .. } => {
+            // The kind is more specific than the status, which is 429 for both
+            // rate limits and an exhausted quota.
+            match (kind.as_deref().unwrap_or_default(), status) {
+                // Waiting does not help until the billing is changed.
+                ("insufficient_quota", _) => None,
+                (kind, _) if kind.starts_with("rate_limit") => Some(RetryOn::RateLimit),
+                ("server_error" | "api_error", _) => Some(RetryOn::ServerError),
+                ("overloaded_error", _) => Some(RetryOn::Overloaded),
+                (_, Some(429)) => Some(RetryOn::RateLimit),
+                (_, Some(500 | 502 | 503 | 504)) => Some(RetryOn::ServerError),
+                (_, Some(529)) => Some(RetryOn::Overloaded),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+impl Retry {
+    /// How long to wait before try `attempt + 1` after try `attempt` failed with `error`,
+    /// or `None` if the request should not be sent again.
+    pub fn delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let condition = condition(error)?;
+        if !self.on.contains(&condition) {
+            return None;
+        }
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        if let Error::Api {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            // The server knows best, but waiting minutes for an answer is pointless.
+            return (*retry_after <= max_delay).then_some(*retry_after);
+        }
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = Duration::from_millis(self.base_delay_ms.saturating_mul(1 << exponent));
+        let jitter = delay.mul_f64(self.jitter.clamp(0.0, 1.0) * fastrand::f64());
+        Some((delay + jitter).min(max_delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: Option<u16>, kind: Option<&str>) -> Error {
+        Error::Api {
+            status,
+            kind: kind.map(|k| k.to_string()),
+            message: "".to_string(),
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let retry = Retry {
+            max_attempts: 4,
+            jitter: 0.0,
+            ..Retry::default()
+        };
+        let error = api_error(Some(503), None);
+        let delays: Vec<Option<Duration>> = (1..=4).map(|n| retry.delay(n, &error)).collect();
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(delays, vec![ms(500), ms(1000), ms(2000), None]);
+
+        let retry = Retry {
+            jitter: 0.5,
+            ..Retry::default()
+        };
+        let delay = retry.delay(2, &error).unwrap();
+        assert!(Duration::from_millis(1000) <= delay && delay <= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn conditions() {
+        let retry = Retry {
+            on: vec![RetryOn::RateLimit, RetryOn::Overloaded],
+            ..Retry::default()
+        };
+        assert!(retry.delay(1, &api_error(Some(429), None)).is_some());
+        assert!(retry
+            .delay(1, &api_error(None, Some("overloaded_error")))
+            .is_some());
+        assert!(retry
+            .delay(1, &api_error(None, Some("server_error")))
+            .is_none());
+        assert!(retry.delay(1, &api_error(Some(401), None)).is_none());
+        assert!(retry
+            .delay(1, &api_error(Some(429), Some("insufficient_quota")))
+            .is_none());
+        assert!(retry.delay(1, &Error::Http("reset".to_string())).is_none());
+        assert!(Retry::default()
+            .delay(1, &Error::Http("reset".to_string()))
+            .is_some());
+        assert!(Retry::default()
+            .delay(1, &Error::Protocol("{}".to_string()))
+            .is_none());
+    }
+
+    #[test]
+    fn retry_after_is_honoured() {
+        let retry = Retry::default();
+        let error = |secs| Error::Api {
+            status: Some(429),
+            kind: None,
+            message: "Slow down".to_string(),
+            retry_after: Some(Duration::from_secs(secs)),
+        };
+        assert_eq!(retry.delay(1, &error(7)), Some(Duration::from_secs(7)));
+        assert_eq!(retry.delay(1, &error(3600)), None);
+    }
+}
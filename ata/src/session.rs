@@ -0,0 +1,326 @@
+//! Session transcripts stored as JSONL in the data directory.
+//!
+//! The first line of a transcript describes the session and each next line
+//! contains one prompt or response.
+
+use ata::backend::Message;
+use ata::backend::Role;
+use ata::config;
+use ata::config::Config;
+use ata::Error;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Session {
+        id: String,
+        time: String,
+    },
+    Message {
+        time: String,
+        role: Role,
+        content: String,
+        /// The parameters that were used to generate a response.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        model: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_tokens: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        temperature: Option<f64>,
+    },
+}
+
+/// Summary of a stored session for `--list-sessions`.
+pub struct Summary {
+    pub id: String,
+    pub time: String,
+    pub prompts: usize,
+    pub first_prompt: String,
+}
+
+pub struct Session {
+    id: String,
+    /// Opened on the first message so that empty sessions are not stored.
+    file: Option<File>,
+}
+
+fn sessions_dir() -> ata::Result<PathBuf> {
+    Ok(config::get_data_dir()?.join("sessions"))
+}
+
+fn session_path(id: &str) -> ata::Result<PathBuf> {
+    // The id can come from `--resume`, so it should not point outside the directory.
+    if id.is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+        return Err(Error::Config {
+            message: format!("Invalid session id `{id}`"),
+            hint: Some("See `ata --list-sessions` for the ids.".to_string()),
+        });
+    }
+    Ok(sessions_dir()?.join(format!("{id}.jsonl")))
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date.
+/// Based on <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 timestamp in UTC.
+fn format_time(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    let (hour, minute, second) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// A session id such as `20240501-132005` which sorts chronologically.
+fn new_id(secs: i64) -> String {
+    format_time(secs)
+        .trim_end_matches('Z')
+        .replace(['-', ':'], "")
+        .replace('T', "-")
+}
+
+fn parse(contents: &str) -> Result<Vec<Record>, String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| format!("Invalid record on line {}: {e}", i + 1))
+        })
+        .collect()
+}
+
+fn messages(records: &[Record]) -> Vec<Message> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Message { role, content, .. } => Some(Message {
+                role: *role,
+                content: content.clone(),
+                images: vec![],
+            }),
+            Record::Session { .. } => None,
+        })
+        .collect()
+}
+
+/// The ids of the stored sessions from old to new.
+fn ids() -> Vec<String> {
+    let dir = match sessions_dir() {
+        Ok(dir) => dir,
+        Err(_) => return vec![],
+    };
+    let mut ids: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".jsonl").map(|id| id.to_string())
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    ids.sort();
+    ids
+}
+
+fn read(id: &str) -> Result<Vec<Record>, String> {
+    let path = session_path(id).map_err(|e| e.to_string())?;
+    let contents = fs::read_to_string(&path).map_err(|e| {
+        format!("Could not read session {id} at {path:?}: {e}\nSee `ata --list-sessions`.")
+    })?;
+    parse(&contents)
+}
+
+/// Summaries of the stored sessions from old to new.
+pub fn list() -> Vec<Summary> {
+    ids()
+        .into_iter()
+        .filter_map(|id| {
+            let records = read(&id).ok()?;
+            let time = match records.first() {
+                Some(Record::Session { time, .. }) => time.clone(),
+                _ => String::new(),
+            };
+            let prompts: Vec<Message> = messages(&records)
+                .into_iter()
+                .filter(|m| m.role == Role::User)
+                .collect();
+            let first_prompt = match prompts.first() {
+                Some(m) => m.content.lines().next().unwrap_or_default().to_string(),
+                None => String::new(),
+            };
+            Some(Summary {
+                id,
+                time,
+                prompts: prompts.len(),
+                first_prompt,
+            })
+        })
+        .collect()
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            id: new_id(now()),
+            file: None,
+        }
+    }
+
+    /// Open a stored session, or the most recent one if `id` is `None`,
+    /// and return the messages in it.
+    pub fn resume(id: Option<&str>) -> Result<(Session, Vec<Message>), String> {
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => match ids().pop() {
+                Some(id) => id,
+                None => return Err("There are no stored sessions yet.".to_string()),
+            },
+        };
+        let records = read(&id)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(session_path(&id).map_err(|e| e.to_string())?)
+            .map_err(|e| format!("Could not open session {id}: {e}"))?;
+        let session = Session {
+            id,
+            file: Some(file),
+        };
+        Ok((session, messages(&records)))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn create_file(&mut self) -> std::io::Result<File> {
+        let dir = sessions_dir().map_err(|e| std::io::Error::other(e.to_string()))?;
+        fs::create_dir_all(&dir)?;
+        let base = self.id.clone();
+        let mut n = 1;
+        loop {
+            let result = OpenOptions::new()
+                .append(true)
+                .create_new(true)
+                .open(dir.join(format!("{}.jsonl", self.id)));
+            match result {
+                Ok(mut file) => {
+                    let header = Record::Session {
+                        id: self.id.clone(),
+                        time: format_time(now()),
+                    };
+                    writeln!(file, "{}", serde_json::to_string(&header)?)?;
+                    return Ok(file);
+                }
+                // Another session was started in the same second.
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    self.id = format!("{base}-{n}");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.file = Some(self.create_file()?);
+        }
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        file.flush()
+    }
+
+    /// Append a prompt and its response to the transcript, along with the
+    /// parameters from `config` that were used for the response.
+    /// Prompts without a response are not stored, so `--resume` does not send them again.
+    pub fn record(&mut self, prompt: &Message, response: &Message, config: &Config) {
+        let time = format_time(now());
+        let prompt = Record::Message {
+            time: time.clone(),
+            role: prompt.role,
+            content: prompt.content.clone(),
+            model: None,
+            max_tokens: None,
+            temperature: None,
+        };
+        let response = Record::Message {
+            time,
+            role: response.role,
+            content: response.content.clone(),
+            model: Some(config.model.clone()),
+            max_tokens: Some(config.max_tokens),
+            temperature: Some(config.temperature),
+        };
+        if let Err(e) = self.write(&prompt).and_then(|_| self.write(&response)) {
+            eprintln!("Could not save the session transcript: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(1714569605), "2024-05-01T13:20:05Z");
+        assert_eq!(new_id(1714569605), "20240501-132005");
+    }
+
+    #[test]
+    fn ids_stay_in_the_sessions_directory() {
+        for id in ["../../x", "a/b", "a\\b", "..", ""] {
+            assert!(session_path(id).is_err(), "{id}");
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let contents = "\
+            {\"type\":\"session\",\"id\":\"20240501-132005\",\"time\":\"2024-05-01T13:20:05Z\"}\n\
+            {\"type\":\"message\",\"time\":\"2024-05-01T13:20:10Z\",\"role\":\"user\",\"content\":\"Hi\"}\n\
+            {\"type\":\"message\",\"time\":\"2024-05-01T13:20:12Z\",\"role\":\"assistant\",\"content\":\"Hello!\",\"model\":\"gpt-4\",\"max_tokens\":100,\"temperature\":0.8}\n";
+        let records = parse(contents).unwrap();
+        assert_eq!(
+            messages(&records),
+            vec![Message::user("Hi"), Message::assistant("Hello!")]
+        );
+        let written: Vec<String> = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap())
+            .collect();
+        assert_eq!(written.join("\n") + "\n", contents);
+    }
+}
@@ -0,0 +1,227 @@
+//! Incremental decoder for server-sent events (SSE).
+//!
+//! The body of a streaming response arrives in TCP chunks which do not line
+//! up with the events nor with the UTF-8 characters inside them. The decoder
+//! therefore keeps the bytes of an unfinished line around until the next
+//! chunk arrives. Since a newline byte never occurs inside a multi-byte UTF-8
+//! sequence, complete lines are always valid text.
+//!
+//! See <https://html.spec.whatwg.org/multipage/server-sent-events.html>.
+
+/// Data that the server sends to mark the end of the stream.
+const DONE: &str = "[DONE]";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    /// Value of the `event:` field if the server sent one.
+    pub event: Option<String>,
+    /// Values of the `data:` fields joined by newlines.
+    pub data: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+    at_start: bool,
+    done: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            at_start: true,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the server sent the `[DONE]` sentinel.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Add a chunk of the body and return the events that were completed by it.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buffer.len() {
+            let end = match self.buffer[i] {
+                b'\n' => i + 1,
+                b'\r' => match self.buffer.get(i + 1) {
+                    Some(b'\n') => i + 2,
+                    Some(_) => i + 1,
+                    // Wait for the next chunk to see whether a `\n` follows.
+                    None => break,
+                },
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let line = String::from_utf8_lossy(&self.buffer[start..i]).to_string();
+            self.process_line(&line, &mut events);
+            start = end;
+            i = end;
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    /// Return the last event when the stream ends without a trailing blank line.
+    pub fn finish(&mut self) -> Vec<Event> {
+        let mut events = vec![];
+        if !self.buffer.is_empty() {
+            let rest = std::mem::take(&mut self.buffer);
+            let line = String::from_utf8_lossy(&rest).to_string();
+            self.process_line(line.trim_end_matches('\r'), &mut events);
+        }
+        self.process_line("", &mut events);
+        events
+    }
+
+    fn process_line(&mut self, line: &str, events: &mut Vec<Event>) {
+        let line = if self.at_start {
+            self.at_start = false;
+            line.trim_start_matches('\u{feff}')
+        } else {
+            line
+        };
+        if self.done {
+            return;
+        }
+        if line.is_empty() {
+            self.dispatch(events);
+            return;
+        }
+        if line.starts_with(':') {
+            // Comment such as `: OPENROUTER PROCESSING` to keep the connection alive.
+            return;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" => self.event = Some(value.to_string()),
+            // `id` and `retry` are only useful for reconnecting.
+            _ => (),
+        }
+    }
+
+    fn dispatch(&mut self, events: &mut Vec<Event>) {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return;
+        }
+        let data = self.data.join("\n");
+        self.data.clear();
+        if data == DONE {
+            self.done = true;
+            return;
+        }
+        events.push(Event { event, data });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const STREAM: &str = "\
+        : keep-alive comment\n\
+        \n\
+        data: {\"content\":\"Hé\"}\n\
+        \n\
+        event: content_block_delta\r\n\
+        data: {\"text\":\"日本語 🦀\"}\r\n\
+        \r\n\
+        data: first line\n\
+        data:second line\n\
+        \n\
+        data: [DONE]\n\
+        \n\
+        data: ignored\n\
+        \n";
+
+    fn expected() -> Vec<Event> {
+        vec![
+            Event {
+                event: None,
+                data: r#"{"content":"Hé"}"#.to_string(),
+            },
+            Event {
+                event: Some("content_block_delta".to_string()),
+                data: r#"{"text":"日本語 🦀"}"#.to_string(),
+            },
+            Event {
+                event: None,
+                data: "first line\nsecond line".to_string(),
+            },
+        ]
+    }
+
+    fn decode(chunks: &[&[u8]]) -> (Vec<Event>, bool) {
+        let mut decoder = Decoder::new();
+        let mut events = vec![];
+        for chunk in chunks {
+            events.extend(decoder.feed(chunk));
+        }
+        events.extend(decoder.finish());
+        (events, decoder.is_done())
+    }
+
+    #[test]
+    fn whole_stream() {
+        let (events, done) = decode(&[STREAM.as_bytes()]);
+        assert_eq!(events, expected());
+        assert!(done);
+    }
+
+    #[test]
+    fn split_at_every_byte() {
+        let bytes = STREAM.as_bytes();
+        for i in 0..=bytes.len() {
+            let (events, done) = decode(&[&bytes[..i], &bytes[i..]]);
+            assert_eq!(events, expected(), "split at byte {i}");
+            assert!(done);
+        }
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let chunks: Vec<&[u8]> = STREAM.as_bytes().chunks(1).collect();
+        let (events, done) = decode(&chunks);
+        assert_eq!(events, expected());
+        assert!(done);
+    }
+
+    #[test]
+    fn irregular_chunks() {
+        let bytes = STREAM.as_bytes();
+        for size in [2, 3, 5, 7, 13] {
+            let chunks: Vec<&[u8]> = bytes.chunks(size).collect();
+            let (events, _) = decode(&chunks);
+            assert_eq!(events, expected(), "chunk size {size}");
+        }
+    }
+
+    #[test]
+    fn missing_trailing_blank_line() {
+        let (events, done) = decode(&[b"data: a\n\ndata: b"]);
+        let data: Vec<String> = events.into_iter().map(|e| e.data).collect();
+        assert_eq!(data, vec!["a", "b"]);
+        assert!(!done);
+    }
+
+    #[test]
+    fn carriage_returns_only() {
+        let (events, _) = decode(&[b"data: a\r", b"\rdata: b\r\r"]);
+        let data: Vec<String> = events.into_iter().map(|e| e.data).collect();
+        assert_eq!(data, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,84 @@
+//! Showing the token usage and cost of responses and of the whole session.
+
+use ata::backend::Usage;
+use ata::config::Config;
+
+/// The tokens and, if known, the cost in dollars.
+pub fn describe(usage: &Usage, cost: Option<f64>) -> String {
+    let tokens = format!(
+        "{} prompt + {} completion = {} tokens",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.total_tokens()
+    );
+    match cost {
+        Some(cost) => format!("{tokens} (${cost:.4})"),
+        None => tokens,
+    }
+}
+
+/// The usage of all responses in a session.
+#[derive(Debug, Default)]
+pub struct Tally {
+    responses: u64,
+    usage: Usage,
+    cost: f64,
+    /// Number of responses of models without a price in the configuration.
+    unpriced: u64,
+}
+
+impl Tally {
+    /// Add a response from the model in `config`.
+    pub fn add(&mut self, usage: &Usage, config: &Config) {
+        self.responses += 1;
+        self.usage += *usage;
+        match config.price() {
+            Some(price) => self.cost += price.cost(usage),
+            None => self.unpriced += 1,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let cost = if self.unpriced == self.responses {
+            None
+        } else {
+            Some(self.cost)
+        };
+        let responses = match self.responses {
+            1 => "1 response".to_string(),
+            n => format!("{n} responses"),
+        };
+        let mut summary = format!("Usage of {responses}: {}", describe(&self.usage, cost));
+        if cost.is_some() && self.unpriced > 0 {
+            summary.push_str(", where the cost excludes models without a price in `[prices]`");
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    #[test]
+    fn session_totals() {
+        let mut config = test_config("[prices]\n\"gpt-4\" = { input = 2.5, output = 10 }");
+        let usage = Usage {
+            prompt_tokens: 1000,
+            completion_tokens: 200,
+        };
+        let mut tally = Tally::default();
+        tally.add(&usage, &config);
+        tally.add(&usage, &config);
+        assert_eq!(
+            tally.summary(),
+            "Usage of 2 responses: 2000 prompt + 400 completion = 2400 tokens ($0.0090)"
+        );
+        config.model = "llama3.2".to_string();
+        tally.add(&usage, &config);
+        assert!(tally
+            .summary()
+            .ends_with("($0.0090), where the cost excludes models without a price in `[prices]`"));
+    }
+}